@@ -1,4 +1,86 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 fn main() {
     // Since we embed the migrations, force cargo to recompile when any change in the directory.
-    println!("cargo:rerun-if-changed=migrations")
+    println!("cargo:rerun-if-changed=migrations");
+    embed_down_migrations();
+
+    // Build information for `/monitoring/info`. Declaring any `rerun-if-changed` turns off rerunning on every
+    // change in the package, so `src` is listed too, keeping the build timestamp current on incremental builds.
+    println!("cargo:rerun-if-changed=src");
+    for git_path in [".git/HEAD", ".git/refs"] {
+        if Path::new(git_path).exists() {
+            println!("cargo:rerun-if-changed={git_path}");
+        }
+    }
+
+    let git_commit = command_output("git", &["rev-parse", "HEAD"]).unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=SHVA_GIT_COMMIT={git_commit}");
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let rustc_version = command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=SHVA_RUSTC_VERSION={rustc_version}");
+
+    let build_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the unix epoch")
+        .as_secs();
+    println!("cargo:rustc-env=SHVA_BUILD_TIMESTAMP={build_timestamp}");
+
+    let mut features: Vec<_> = env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(|feature| feature.to_lowercase()))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=SHVA_CARGO_FEATURES={}", features.join(","));
+}
+
+/// refinery only embeds `V<version>__<name>.sql`, so the paired `V<version>__<name>.down.sql` rollback scripts are
+/// embedded here, as `DOWN_MIGRATIONS: &[(version, name, sql)]`.
+fn embed_down_migrations() {
+    let mut down_migrations: Vec<(String, String, PathBuf)> = fs::read_dir("migrations")
+        .expect("failed to read the migrations directory")
+        .filter_map(|entry| {
+            let path = entry.expect("failed to read the migrations directory").path();
+            let file_name = path.file_name()?.to_str()?;
+            let (version, name) = file_name
+                .strip_prefix('V')?
+                .strip_suffix(".down.sql")?
+                .split_once("__")?;
+            Some((version.to_owned(), name.to_owned(), path.canonicalize().ok()?))
+        })
+        .collect();
+    down_migrations.sort();
+
+    let entries: String = down_migrations
+        .iter()
+        .map(|(version, name, path)| {
+            let version: u64 = version
+                .parse()
+                .unwrap_or_else(|_| panic!("invalid version in down migration {}", path.display()));
+            format!(
+                "    ({version}, {name:?}, include_str!({:?})),\n",
+                path.display().to_string()
+            )
+        })
+        .collect();
+
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR is set by cargo");
+    fs::write(
+        Path::new(&out_dir).join("down_migrations.rs"),
+        format!("pub const DOWN_MIGRATIONS: &[(SchemaVersion, &str, &str)] = &[\n{entries}];\n"),
+    )
+    .expect("failed to write the embedded down migrations");
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
 }
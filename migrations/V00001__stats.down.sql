@@ -0,0 +1,2 @@
+-- Stats table
+DROP TABLE stats;
@@ -23,7 +23,10 @@
  *
  */
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
 
 use axum::{
     http::{Response, StatusCode},
@@ -34,14 +37,21 @@ use tower_http::validate_request::ValidateRequest;
 
 #[derive(Clone)]
 pub struct ApiKeyAuth {
-    apikeys: HashMap<String, String>,
+    apikeys: Arc<RwLock<HashMap<String, String>>>,
 }
 
 const APIKEY_HEADER: &str = "x-auth-api-key";
 
 impl ApiKeyAuth {
     pub fn from_apikeys(apikeys: HashMap<String, String>) -> Self {
-        Self { apikeys }
+        Self {
+            apikeys: Arc::new(RwLock::new(apikeys)),
+        }
+    }
+
+    /// Replaces the API keys of this instance and all its clones.
+    pub fn reload(&self, apikeys: HashMap<String, String>) {
+        *self.apikeys.write().unwrap() = apikeys;
     }
 }
 
@@ -56,8 +66,8 @@ impl<B> ValidateRequest<B> for ApiKeyAuth {
             .headers()
             .get(APIKEY_HEADER)
             .and_then(|key| key.to_str().ok())
-            .and_then(|key| self.apikeys.get(key))
-            .map(|user_id| UserId(user_id.clone()));
+            .and_then(|key| self.apikeys.read().unwrap().get(key).cloned())
+            .map(UserId);
         match user_id {
             Some(user_id) => {
                 request.extensions_mut().insert(user_id);
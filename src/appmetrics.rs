@@ -23,38 +23,278 @@
  *
  */
 
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
 
 use axum::{
+    body::{Body, Bytes, HttpBody},
     extract::{Extension, MatchedPath, Request},
+    http::{HeaderMap, header},
     middleware::Next,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
+};
+use http_body::{Frame, SizeHint};
+use metrics::{Gauge, Histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle, PrometheusRecorder};
+use metrics_util::{
+    MetricKindMask,
+    layers::{Layer, PrefixLayer},
 };
-use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use opentelemetry::trace::TraceContextExt;
 use tokio::{sync::Semaphore, time::Instant};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
-use crate::{apikey_auth::UserId, db::ConnectionPool};
+use crate::{
+    apikey_auth::UserId,
+    config::{LabelLimitConfig, MetricsConfig},
+    db::ConnectionPool,
+    openmetrics::{self, Exemplars, TraceId},
+};
 
 const METRIC_HTTP_REQUEST_DURATION: &str = "http_request_duration_seconds";
-const METRIC_HTTP_REQUEST_DURATION_BUCKETS: &[f64; 4] = &[0.1, 0.25, 0.5, 1.0];
+const METRIC_HTTP_REQUEST_DURATION_BUCKETS: &[f64; 8] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0];
+const METRIC_HTTP_REQUEST_SIZE: &str = "http_request_size_bytes";
+const METRIC_HTTP_RESPONSE_SIZE: &str = "http_response_size_bytes";
+const METRIC_HTTP_SIZE_BUCKETS: &[f64; 8] = &[64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0];
+const METRIC_HTTP_REQUESTS_IN_FLIGHT: &str = "http_requests_in_flight";
+const METRIC_LABEL_VALUES_DROPPED: &str = "metrics_label_values_dropped_total";
+
+const LABEL_USERID: &str = "userid";
+const LABEL_PATH: &str = "path";
+const LABEL_VALUE_OTHER: &str = "other";
+/// Labels which can be limited with `[metrics.label_limits.<label>]`.
+pub const LIMITABLE_LABELS: &[&str] = &[LABEL_USERID, LABEL_PATH];
+
+/// The histograms and their buckets used when the metric is not listed in `[metrics.buckets]`.
+const DEFAULT_BUCKETS: &[(&str, &[f64])] = &[
+    (METRIC_HTTP_REQUEST_DURATION, METRIC_HTTP_REQUEST_DURATION_BUCKETS),
+    (METRIC_HTTP_REQUEST_SIZE, METRIC_HTTP_SIZE_BUCKETS),
+    (METRIC_HTTP_RESPONSE_SIZE, METRIC_HTTP_SIZE_BUCKETS),
+];
+
+/// The metric name as rendered. The prefix layer rewrites metric names before they reach the recorder, so
+/// anything matching on rendered names (bucket matchers, exemplars) must use the prefixed name.
+fn prefixed(config: &MetricsConfig, name: &str) -> String {
+    match &config.prefix {
+        Some(prefix) => format!("{prefix}_{name}"),
+        None => name.to_owned(),
+    }
+}
+
+/// Histograms whose buckets can be set with `[metrics.buckets]`.
+pub fn histograms() -> impl Iterator<Item = &'static str> {
+    DEFAULT_BUCKETS.iter().map(|(name, _)| *name)
+}
+
+/// The configured or default buckets of the histogram `name`. Empty when it is rendered as a summary.
+fn effective_buckets(config: &MetricsConfig, name: &str) -> Vec<f64> {
+    match config.buckets.get(name) {
+        Some(buckets) => buckets.clone(),
+        None => DEFAULT_BUCKETS
+            .iter()
+            .find(|(default_name, _)| *default_name == name)
+            .map(|(_, buckets)| buckets.to_vec())
+            .unwrap_or_default(),
+    }
+}
+
+/// Counter and histogram series not updated for this long are no longer exported, so that the series of label values
+/// evicted by a `LabelGuard` go away. Gauges are kept, as some are only set once (e.g. `build_info`).
+const SERIES_IDLE_TIMEOUT: Duration = Duration::from_secs(3 * LABEL_RERANK_INTERVAL.as_secs());
+
+pub(crate) fn install_prometheus(config: &MetricsConfig) -> anyhow::Result<PrometheusHandle> {
+    let recorder = prometheus_recorder(config, SERIES_IDLE_TIMEOUT)?;
+    let handle = recorder.handle();
+
+    match &config.prefix {
+        Some(prefix) => metrics::set_global_recorder(PrefixLayer::new(prefix.clone()).layer(recorder))?,
+        None => metrics::set_global_recorder(recorder)?,
+    }
 
-pub(crate) fn install_prometheus() -> Result<PrometheusHandle, metrics_exporter_prometheus::BuildError> {
-    PrometheusBuilder::new()
-        .set_buckets_for_metric(
-            Matcher::Full(METRIC_HTTP_REQUEST_DURATION.into()),
-            METRIC_HTTP_REQUEST_DURATION_BUCKETS,
-        )?
-        .install_recorder()
+    Ok(handle)
 }
 
-pub async fn track_latency(req: Request, next: Next) -> impl IntoResponse {
+fn prometheus_recorder(config: &MetricsConfig, idle_timeout: Duration) -> anyhow::Result<PrometheusRecorder> {
+    let prefixed = |name: &str| prefixed(config, name);
+
+    let mut builder =
+        PrometheusBuilder::new().idle_timeout(MetricKindMask::COUNTER | MetricKindMask::HISTOGRAM, Some(idle_timeout));
+
+    for name in histograms() {
+        // Without buckets, the histogram is rendered as a summary with `quantiles`.
+        let buckets = effective_buckets(config, name);
+        if !buckets.is_empty() {
+            builder = builder.set_buckets_for_metric(Matcher::Full(prefixed(name)), &buckets)?;
+        }
+    }
+
+    if let Some(quantiles) = &config.quantiles {
+        builder = builder.set_quantiles(quantiles)?;
+    }
+
+    for (key, value) in &config.global_labels {
+        builder = builder.add_global_label(key, value);
+    }
+
+    Ok(builder.build_recorder())
+}
+
+/// Exemplar tracking for the request latency histogram.
+pub fn request_duration_exemplars(config: &MetricsConfig) -> Exemplars {
+    Exemplars::new(
+        prefixed(config, METRIC_HTTP_REQUEST_DURATION),
+        vec!["method", LABEL_PATH, "code", LABEL_USERID],
+        effective_buckets(config, METRIC_HTTP_REQUEST_DURATION),
+    )
+}
+
+/// How often the tracked values of a label are re-ranked by frequency.
+const LABEL_RERANK_INTERVAL: Duration = Duration::from_secs(60);
+/// Distinct values counted per tracked slot, bounding the memory used to rank values.
+const LABEL_CANDIDATES_PER_SLOT: usize = 10;
+
+/// Bounds the number of distinct values of a metric label to the `max_values` most frequent ones.
+///
+/// Values are counted as they are seen. Every `LABEL_RERANK_INTERVAL`, the most frequent values take the slots and
+/// the counts are halved, so that values which stopped being used are evicted over time. Until then, free slots go
+/// to new values as they come. Allowlisted values are always tracked. Any other value is replaced by `other` and
+/// counted in `metrics_label_values_dropped_total`.
+struct LabelGuard {
+    label: &'static str,
+    max_values: usize,
+    allowlist: HashSet<String>,
+    state: Mutex<LabelGuardState>,
+}
+
+struct LabelGuardState {
+    tracked: HashSet<String>,
+    counts: HashMap<String, u64>,
+    ranked_at: Instant,
+}
+
+impl LabelGuard {
+    fn new(label: &'static str, config: &LabelLimitConfig) -> Self {
+        Self {
+            label,
+            max_values: config.max_values,
+            allowlist: config.allowlist.iter().cloned().collect(),
+            state: Mutex::new(LabelGuardState {
+                tracked: HashSet::new(),
+                counts: HashMap::new(),
+                ranked_at: Instant::now(),
+            }),
+        }
+    }
+
+    fn admit(&self, value: String) -> String {
+        self.admit_at(value, Instant::now())
+    }
+
+    fn admit_at(&self, value: String, now: Instant) -> String {
+        if self.allowlist.contains(&value) {
+            return value;
+        }
+
+        let mut state = self.state.lock().expect("label guard lock poisoned");
+        let max_candidates = self.max_values.saturating_mul(LABEL_CANDIDATES_PER_SLOT);
+        if let Some(count) = state.counts.get_mut(&value) {
+            *count += 1;
+        } else if state.counts.len() < max_candidates {
+            state.counts.insert(value.clone(), 1);
+        }
+
+        if now.duration_since(state.ranked_at) >= LABEL_RERANK_INTERVAL {
+            self.rerank(&mut state);
+            state.ranked_at = now;
+        }
+
+        if state.tracked.contains(&value) {
+            return value;
+        }
+        if state.tracked.len() < self.max_values {
+            state.tracked.insert(value.clone());
+            return value;
+        }
+        drop(state);
+
+        metrics::counter!(METRIC_LABEL_VALUES_DROPPED, "label" => self.label).increment(1);
+        LABEL_VALUE_OTHER.into()
+    }
+
+    /// Gives the slots to the most frequent values (ties broken by value, for stability), then halves the counts.
+    fn rerank(&self, state: &mut LabelGuardState) {
+        let mut ranked: Vec<_> = state.counts.iter().collect();
+        ranked.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+        state.tracked = ranked
+            .into_iter()
+            .take(self.max_values)
+            .map(|(value, _)| value.clone())
+            .collect();
+
+        state.counts.retain(|_, count| {
+            *count /= 2;
+            *count > 0
+        });
+    }
+}
+
+/// Cardinality guards for the request labels, as configured in `[metrics.label_limits]`.
+/// Labels without a configured limit are not guarded.
+pub struct LabelGuards {
+    userid: Option<LabelGuard>,
+    path: Option<LabelGuard>,
+}
+
+impl LabelGuards {
+    pub fn from_config(config: &MetricsConfig) -> Self {
+        let guard = |label| {
+            config
+                .label_limits
+                .get(label)
+                .map(|limit| LabelGuard::new(label, limit))
+        };
+        Self {
+            userid: guard(LABEL_USERID),
+            path: guard(LABEL_PATH),
+        }
+    }
+
+    fn admit(guard: &Option<LabelGuard>, value: String) -> String {
+        match guard {
+            Some(guard) => guard.admit(value),
+            None => value,
+        }
+    }
+}
+
+pub async fn track_latency(
+    Extension(label_guards): Extension<Arc<LabelGuards>>,
+    Extension(exemplars): Extension<Arc<Exemplars>>,
+    req: Request,
+    next: Next,
+) -> impl IntoResponse {
     let path = match req.extensions().get::<MatchedPath>() {
         Some(path) => path.as_str().to_owned(),
         None => "*".into(),
     };
+    let path = LabelGuards::admit(&label_guards.path, path);
 
     let method = req.method().as_str().to_owned();
 
+    let request_labels = [("method", method.clone()), (LABEL_PATH, path.clone())];
+    let req = record_request_size(req, metrics::histogram!(METRIC_HTTP_REQUEST_SIZE, &request_labels));
+
+    let _in_flight = InFlightGuard::new(metrics::gauge!(METRIC_HTTP_REQUESTS_IN_FLIGHT, LABEL_PATH => path.clone()));
+
     // Measure latency
     let now = Instant::now();
     let response = next.run(req).await;
@@ -63,15 +303,153 @@ pub async fn track_latency(req: Request, next: Next) -> impl IntoResponse {
         Some(UserId(user_id)) => user_id.clone(),
         None => "UNAUTHORIZED".into(),
     };
+    let user_id = LabelGuards::admit(&label_guards.userid, user_id);
 
     let duration = now.elapsed().as_secs_f64();
 
     let code = response.status().as_u16().to_string();
 
-    let labels = [("method", method), ("path", path), ("code", code), ("userid", user_id)];
+    let response_labels = [
+        ("method", method.clone()),
+        ("path", path.clone()),
+        ("code", code.clone()),
+    ];
+    let response = record_response_size(
+        response,
+        metrics::histogram!(METRIC_HTTP_RESPONSE_SIZE, &response_labels),
+    );
+
+    let labels = [
+        ("method", method),
+        (LABEL_PATH, path),
+        ("code", code),
+        (LABEL_USERID, user_id),
+    ];
 
     metrics::histogram!(METRIC_HTTP_REQUEST_DURATION, &labels).record(duration);
 
+    if let Some(TraceId(trace_id)) = response.extensions().get::<TraceId>() {
+        exemplars.record(&labels, duration, trace_id.clone());
+    }
+
+    response
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+fn record_request_size(req: Request, histogram: Histogram) -> Request {
+    if let Some(size) = content_length(req.headers()) {
+        histogram.record(size as f64);
+        return req;
+    }
+
+    let (parts, body) = req.into_parts();
+    Request::from_parts(parts, Body::new(SizeRecordingBody::new(body, histogram)))
+}
+
+fn record_response_size(response: Response, histogram: Histogram) -> Response {
+    if let Some(size) = content_length(response.headers()) {
+        histogram.record(size as f64);
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    Response::from_parts(parts, Body::new(SizeRecordingBody::new(body, histogram)))
+}
+
+/// Body wrapper for bodies without a `Content-Length` (e.g. streamed or compressed responses).
+/// Counts the data bytes as they are polled and records the total to the histogram when dropped.
+struct SizeRecordingBody {
+    inner: Body,
+    size: u64,
+    histogram: Histogram,
+}
+
+impl SizeRecordingBody {
+    fn new(inner: Body, histogram: Histogram) -> Self {
+        Self {
+            inner,
+            size: 0,
+            histogram,
+        }
+    }
+}
+
+impl HttpBody for SizeRecordingBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll
+            && let Some(data) = frame.data_ref()
+        {
+            self.size += data.len() as u64;
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for SizeRecordingBody {
+    fn drop(&mut self) {
+        self.histogram.record(self.size as f64);
+    }
+}
+
+static IN_FLIGHT_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of requests currently being served, across all routes.
+pub fn in_flight_requests() -> usize {
+    IN_FLIGHT_REQUESTS.load(Ordering::Relaxed)
+}
+
+/// Increments the in-flight gauge on creation and decrements it on drop, so that requests which are
+/// cancelled mid-flight (e.g. client disconnect) are also accounted for.
+struct InFlightGuard(Gauge);
+
+impl InFlightGuard {
+    fn new(gauge: Gauge) -> Self {
+        gauge.increment(1);
+        IN_FLIGHT_REQUESTS.fetch_add(1, Ordering::Relaxed);
+        Self(gauge)
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.decrement(1);
+        IN_FLIGHT_REQUESTS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Passes the trace id of the request span on to `track_latency`, which runs outside of the span.
+pub async fn trace_id_snooper(req: Request, next: Next) -> impl IntoResponse {
+    let span_context = Span::current().context().span().span_context().clone();
+
+    let mut response = next.run(req).await;
+
+    if span_context.is_valid() && span_context.is_sampled() {
+        response
+            .extensions_mut()
+            .insert(TraceId(span_context.trace_id().to_string()));
+    }
+
     response
 }
 
@@ -93,12 +471,174 @@ fn update_global_concurrency_metric_gauge(semaphore: Arc<Semaphore>) {
 }
 
 pub async fn scrape(
+    headers: HeaderMap,
     Extension(prometheus_handle): Extension<Arc<PrometheusHandle>>,
+    Extension(exemplars): Extension<Arc<Exemplars>>,
     Extension(pool): Extension<ConnectionPool>,
     Extension(global_concurrency_semapshore): Extension<Arc<Semaphore>>,
-) -> String {
+) -> impl IntoResponse {
     crate::db::update_metric_gauges(&pool);
     update_global_concurrency_metric_gauge(global_concurrency_semapshore);
+    crate::runtime_metrics::update_tokio_metric_gauges();
+    crate::runtime_metrics::update_process_metric_gauges();
+
+    let rendered = prometheus_handle.render();
+    exemplars.evict_idle(SERIES_IDLE_TIMEOUT);
+
+    match openmetrics::accepts_openmetrics(&headers) {
+        true => (
+            [(header::CONTENT_TYPE, openmetrics::CONTENT_TYPE_OPENMETRICS)],
+            openmetrics::render(&rendered, &exemplars),
+        ),
+        false => ([(header::CONTENT_TYPE, openmetrics::CONTENT_TYPE_PROMETHEUS)], rendered),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+    use super::*;
+
+    fn guard(max_values: usize, allowlist: &[&str]) -> LabelGuard {
+        LabelGuard::new(
+            LABEL_USERID,
+            &LabelLimitConfig {
+                max_values,
+                allowlist: allowlist.iter().map(|value| value.to_string()).collect(),
+            },
+        )
+    }
+
+    fn dropped(recorder: &DebuggingRecorder) -> u64 {
+        recorder
+            .snapshotter()
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .find(|(key, ..)| key.key().name() == METRIC_LABEL_VALUES_DROPPED)
+            .map(|(.., value)| match value {
+                DebugValue::Counter(count) => count,
+                value => panic!("unexpected value {value:?}"),
+            })
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn histograms_with_empty_buckets_are_rendered_as_summaries() {
+        let config = MetricsConfig {
+            buckets: HashMap::from([(METRIC_HTTP_REQUEST_SIZE.to_owned(), vec![])]),
+            quantiles: Some(vec![0.5, 0.99]),
+            ..Default::default()
+        };
+        let recorder = prometheus_recorder(&config, SERIES_IDLE_TIMEOUT).unwrap();
+        metrics::with_local_recorder(&recorder, || {
+            metrics::histogram!(METRIC_HTTP_REQUEST_SIZE).record(100.0);
+            metrics::histogram!(METRIC_HTTP_RESPONSE_SIZE).record(100.0);
+        });
+
+        let rendered = recorder.handle().render();
+        assert!(
+            rendered.contains("# TYPE http_request_size_bytes summary"),
+            "{rendered}"
+        );
+        assert!(
+            rendered.contains("http_request_size_bytes{quantile=\"0.99\"}"),
+            "{rendered}"
+        );
+        assert!(
+            rendered.contains("# TYPE http_response_size_bytes histogram"),
+            "{rendered}"
+        );
+        assert!(
+            rendered.contains("http_response_size_bytes_bucket{le=\"256\"} 1"),
+            "{rendered}"
+        );
+    }
+
+    #[test]
+    fn series_stay_bounded_under_label_churn() {
+        const IDLE_TIMEOUT: Duration = Duration::from_millis(50);
+        let recorder = prometheus_recorder(&MetricsConfig::default(), IDLE_TIMEOUT).unwrap();
+        let guard = guard(2, &[]);
+        let started = Instant::now();
+        let mut admitted = HashSet::new();
+
+        for round in 0..6u32 {
+            // Every round brings new values, which take over the slots at the re-rank in the middle of the round.
+            for now in [2 * round, 2 * round + 1].map(|interval| started + LABEL_RERANK_INTERVAL * interval) {
+                metrics::with_local_recorder(&recorder, || {
+                    for user in 0..4 {
+                        for _ in 0..3 {
+                            let value = guard.admit_at(format!("user{round}_{user}"), now);
+                            metrics::counter!("requests_total", LABEL_USERID => value.clone()).increment(1);
+                            admitted.insert(value);
+                        }
+                    }
+                });
+            }
+
+            let rendered = recorder.handle().render();
+            let series = rendered
+                .lines()
+                .filter(|line| line.starts_with("requests_total{"))
+                .count();
+            // At most the two tracked values and `other`.
+            assert!(series <= 3, "{rendered}");
+            std::thread::sleep(IDLE_TIMEOUT * 2);
+        }
+        assert!(admitted.len() > 6, "{admitted:?}");
+    }
 
-    prometheus_handle.render()
+    #[test]
+    fn allowlisted_values_are_tracked_beyond_the_limit() {
+        let guard = guard(1, &["admin"]);
+        assert_eq!(guard.admit("user1".into()), "user1");
+        assert_eq!(guard.admit("admin".into()), "admin");
+        assert_eq!(guard.admit("user2".into()), LABEL_VALUE_OTHER);
+    }
+
+    #[test]
+    fn values_beyond_the_limit_are_reported_as_other_and_counted() {
+        let recorder = DebuggingRecorder::new();
+        metrics::with_local_recorder(&recorder, || {
+            let guard = guard(2, &[]);
+            assert_eq!(guard.admit("user1".into()), "user1");
+            assert_eq!(guard.admit("user2".into()), "user2");
+            assert_eq!(guard.admit("user3".into()), LABEL_VALUE_OTHER);
+            assert_eq!(guard.admit("user4".into()), LABEL_VALUE_OTHER);
+            assert_eq!(guard.admit("user1".into()), "user1");
+        });
+        assert_eq!(dropped(&recorder), 2);
+    }
+
+    #[test]
+    fn most_frequent_values_take_the_slots_on_rerank() {
+        let guard = guard(1, &[]);
+        let start = Instant::now();
+        assert_eq!(guard.admit_at("quiet".into(), start), "quiet");
+        for _ in 0..5 {
+            assert_eq!(guard.admit_at("busy".into(), start), LABEL_VALUE_OTHER);
+        }
+
+        let later = start + LABEL_RERANK_INTERVAL;
+        assert_eq!(guard.admit_at("busy".into(), later), "busy");
+        assert_eq!(guard.admit_at("quiet".into(), later), LABEL_VALUE_OTHER);
+    }
+
+    #[test]
+    fn values_no_longer_seen_are_evicted() {
+        let guard = guard(1, &[]);
+        let mut now = Instant::now();
+        for _ in 0..4 {
+            guard.admit_at("old".into(), now);
+        }
+        // Counts halve on every rerank, so a value seen once per interval eventually outranks the old one.
+        let mut admitted = String::new();
+        for _ in 0..5 {
+            now += LABEL_RERANK_INTERVAL;
+            admitted = guard.admit_at("new".into(), now);
+        }
+        assert_eq!(admitted, "new");
+    }
 }
@@ -25,9 +25,23 @@
 
 use opentelemetry::trace::TracerProvider;
 use opentelemetry_sdk::trace::SdkTracerProvider;
-use tracing_subscriber::{Registry, fmt, fmt::format::FmtSpan, prelude::*};
+use tracing_subscriber::{EnvFilter, Registry, fmt, fmt::format::FmtSpan, prelude::*, reload};
 
-pub fn setup_tracing(service_name: &str) -> anyhow::Result<SdkTracerProvider> {
+/// Handle to replace the log filter of the running service.
+pub type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// The log filter from `log_filter` when set, otherwise from `RUST_LOG`.
+pub fn log_filter(log_filter: Option<&str>) -> anyhow::Result<EnvFilter> {
+    Ok(match log_filter {
+        Some(log_filter) => EnvFilter::try_new(log_filter)?,
+        None => EnvFilter::from_default_env(),
+    })
+}
+
+pub fn setup_tracing(
+    service_name: &str,
+    log_filter_directives: Option<&str>,
+) -> anyhow::Result<(SdkTracerProvider, LogFilterHandle)> {
     let otlp_exporter = opentelemetry_otlp::SpanExporter::builder().with_http().build()?;
 
     let tracer_provider = SdkTracerProvider::builder().with_batch_exporter(otlp_exporter).build();
@@ -39,7 +53,7 @@ pub fn setup_tracing(service_name: &str) -> anyhow::Result<SdkTracerProvider> {
 
     let log_fmt_layer = fmt::layer().with_span_events(FmtSpan::CLOSE);
 
-    let env_filter = tracing_subscriber::filter::EnvFilter::from_default_env();
+    let (env_filter, log_filter_handle) = reload::Layer::new(log_filter(log_filter_directives)?);
 
     let subscriber = Registry::default()
         .with(env_filter)
@@ -48,7 +62,7 @@ pub fn setup_tracing(service_name: &str) -> anyhow::Result<SdkTracerProvider> {
 
     tracing::subscriber::set_global_default(subscriber)?;
 
-    Ok(tracer_provider)
+    Ok((tracer_provider, log_filter_handle))
 }
 
 pub fn setup_basic_logging() -> anyhow::Result<()> {
@@ -58,7 +72,11 @@ pub fn setup_basic_logging() -> anyhow::Result<()> {
         .with_target(true)
         .compact();
 
-    tracing_subscriber::fmt().event_format(format).init();
+    // Commands print their results to stdout, keep logs out of it.
+    tracing_subscriber::fmt()
+        .event_format(format)
+        .with_writer(std::io::stderr)
+        .init();
 
     Ok(())
 }
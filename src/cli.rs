@@ -0,0 +1,144 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2022 Eldad Zack
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ */
+
+use std::{path::PathBuf, process::ExitCode};
+
+use clap::{Parser, Subcommand};
+
+use refinery::SchemaVersion;
+
+use crate::{config::ConfigErrors, database_migrations};
+
+/// Exit code of a command which failed while running (e.g. the database is unreachable).
+pub const EXIT_FAILURE: u8 = 1;
+/// Exit code of invalid arguments, as used by clap.
+pub const EXIT_USAGE: u8 = 2;
+/// Exit code of a missing or invalid config (`EX_CONFIG` from `sysexits.h`).
+pub const EXIT_CONFIG: u8 = 78;
+
+#[derive(Parser)]
+#[command(version, about = "Learning experiment with Axum")]
+pub struct Cli {
+    /// Config file. Defaults to `$SHVA_CONFIG`, otherwise `shva.toml` in the working directory.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the service (the default when no command is given)
+    Serve,
+    /// Apply pending database migrations
+    Migrate {
+        /// Stop after applying this version (e.g. `V3`) instead of applying all pending migrations
+        #[arg(long, value_name = "VERSION", value_parser = database_migrations::parse_version)]
+        target: Option<SchemaVersion>,
+        /// List the migrations which would be applied, in order, with their checksums, without applying them
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Roll back the migrations applied after a version, newest first, in a single transaction
+    Rollback {
+        /// Last version to keep (e.g. `V2`); `V0` rolls back every migration
+        #[arg(long, value_name = "VERSION", value_parser = database_migrations::parse_version)]
+        to: SchemaVersion,
+    },
+    /// Create a migration file with the next version
+    NewMigration {
+        /// Name of the migration, in letters, digits and underscores (e.g. `add_users`)
+        #[arg(value_parser = database_migrations::parse_migration_name)]
+        name: String,
+        /// Also create the down migration
+        #[arg(long)]
+        down: bool,
+        /// Directory of the migrations
+        #[arg(long, value_name = "PATH", default_value = "migrations")]
+        dir: PathBuf,
+    },
+    /// List the database migrations and fail when the applied migrations drifted from the embedded ones
+    CheckMigrations {
+        /// Print a JSON report instead of the list
+        #[arg(long)]
+        json: bool,
+    },
+    /// Verify that the embedded migrations are versioned consecutively
+    VerifyMigrationVersioning {
+        /// Also require a down migration for every migration
+        #[arg(long)]
+        strict: bool,
+    },
+    /// Print a normalized snapshot of the database schema, one line per object
+    SchemaDump,
+    /// Compare schema snapshots, exiting with failure when they differ
+    ///
+    /// Either two dumps (`schema-diff expected.txt actual.txt`), or the schema the migrations produce in an empty
+    /// scratch database with a dump or, when no dump is given, with the database of the config
+    /// (`schema-diff --scratch <CONNECTION_STRING> [actual.txt]`).
+    SchemaDiff {
+        /// Schema dump, from `schema-dump`
+        dump: Option<PathBuf>,
+        /// Second schema dump, compared with the first
+        other_dump: Option<PathBuf>,
+        /// Connection string of an empty scratch database to apply the migrations to
+        #[arg(
+            long,
+            value_name = "CONNECTION_STRING",
+            conflicts_with = "other_dump",
+            required_unless_present = "other_dump"
+        )]
+        scratch: Option<String>,
+    },
+    /// Print the OpenAPI document
+    Openapi,
+    /// Validate the config and print it with secrets redacted
+    ConfigCheck,
+    /// Print the JSON Schema of the config
+    ConfigSchema,
+}
+
+/// Parses the arguments. Help and version are printed with success, usage errors with `EXIT_USAGE`.
+pub fn parse() -> Result<Cli, ExitCode> {
+    Cli::try_parse().map_err(|error| {
+        let _ = error.print();
+        if error.use_stderr() {
+            ExitCode::from(EXIT_USAGE)
+        } else {
+            ExitCode::SUCCESS
+        }
+    })
+}
+
+/// Reports the error and maps it to the exit code.
+pub fn exit_code(error: &anyhow::Error) -> ExitCode {
+    eprintln!("Error: {error:?}");
+    if error.downcast_ref::<ConfigErrors>().is_some() {
+        ExitCode::from(EXIT_CONFIG)
+    } else {
+        ExitCode::from(EXIT_FAILURE)
+    }
+}
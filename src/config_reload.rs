@@ -0,0 +1,377 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2022 Eldad Zack
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ */
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::BoxError;
+use tokio::sync::{Semaphore, watch};
+use tower::{Layer, Service, timeout::error::Elapsed};
+use tracing::{error, info, warn};
+
+use crate::{
+    DEFAULT_MAX_CONCURRENT_CONNECTIONS,
+    apikey_auth::ApiKeyAuth,
+    apptracing::{self, LogFilterHandle},
+    config::{Config, REDACTED},
+};
+
+/// Settings which take effect on reload, as JSON paths into the config. Everything else requires a restart.
+const RELOADABLE: &[&str] = &[
+    "apikeys",
+    "service.log_filter",
+    "service.max_concurrent_connections",
+    "service.request_timeout_milliseconds",
+];
+
+/// Like `tower::timeout::TimeoutLayer`, but the timeout can be changed while the service is running. Timeouts are
+/// reported as `Elapsed`, same as `TimeoutLayer`.
+#[derive(Clone)]
+pub struct ReloadableTimeoutLayer {
+    timeout_milliseconds: Arc<AtomicU64>,
+}
+
+impl ReloadableTimeoutLayer {
+    pub fn new(timeout_milliseconds: Arc<AtomicU64>) -> Self {
+        Self { timeout_milliseconds }
+    }
+}
+
+impl<S> Layer<S> for ReloadableTimeoutLayer {
+    type Service = ReloadableTimeout<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ReloadableTimeout {
+            inner,
+            timeout_milliseconds: self.timeout_milliseconds.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ReloadableTimeout<S> {
+    inner: S,
+    timeout_milliseconds: Arc<AtomicU64>,
+}
+
+impl<S, Request> Service<Request> for ReloadableTimeout<S>
+where
+    S: Service<Request>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let timeout = Duration::from_millis(self.timeout_milliseconds.load(Ordering::Relaxed));
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            match tokio::time::timeout(timeout, response).await {
+                Ok(response) => response.map_err(Into::into),
+                Err(_) => Err(Elapsed::new().into()),
+            }
+        })
+    }
+}
+
+/// Re-reads the config file on SIGHUP and applies the reloadable settings.
+pub struct ConfigReloader {
+    pub config_path: String,
+    pub config: Config,
+    pub apikey_auth: ApiKeyAuth,
+    pub log_filter: Option<LogFilterHandle>,
+    pub request_timeout_milliseconds: Arc<AtomicU64>,
+    pub global_concurrency_semaphore: Arc<Semaphore>,
+}
+
+impl ConfigReloader {
+    /// Reloads on every SIGHUP until shutdown.
+    pub async fn run(mut self, mut shutdown: watch::Receiver<bool>) {
+        #[cfg(unix)]
+        let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Failed to install SIGHUP handler, config reload is disabled: {}", e);
+                return;
+            }
+        };
+
+        loop {
+            #[cfg(unix)]
+            let reload = hangup.recv();
+            #[cfg(not(unix))]
+            let reload = std::future::pending::<Option<()>>();
+
+            let reloading = tokio::select! {
+                _ = reload => true,
+                _ = shutdown.wait_for(|&shutdown| shutdown) => false,
+            };
+            if !reloading {
+                return;
+            }
+            self.reload().await;
+        }
+    }
+
+    async fn reload(&mut self) {
+        info!("Received SIGHUP: reloading config from {}", self.config_path);
+
+        // Reading the config (and secret files) is blocking file I/O, which must not stall the runtime.
+        let config_path = self.config_path.clone();
+        let config = match tokio::task::spawn_blocking(move || Config::read(&config_path))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|result| result)
+        {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Rejected config reload, keeping the current config: {:#}", e);
+                return;
+            }
+        };
+
+        // Build everything that can fail before applying anything, so a reload is all or nothing.
+        let log_filter = match apptracing::log_filter(config.service.log_filter.as_deref()) {
+            Ok(log_filter) => log_filter,
+            Err(e) => {
+                error!("Rejected config reload, keeping the current config: {:#}", e);
+                return;
+            }
+        };
+
+        let changes = match changes(&self.config, &config) {
+            Ok(changes) => changes,
+            Err(e) => {
+                error!("Rejected config reload, keeping the current config: {:#}", e);
+                return;
+            }
+        };
+
+        if changes.is_empty() {
+            info!("Config reloaded: no changes");
+            return;
+        }
+
+        for (change, (current, new)) in &changes {
+            if RELOADABLE.iter().any(|reloadable| is_within(change, reloadable)) {
+                info!("Config reloaded: {} changed: {} -> {}", change, current, new);
+            } else {
+                warn!(
+                    "Config reloaded: {} changed: {} -> {}, but requires a restart to take effect",
+                    change, current, new
+                );
+            }
+        }
+
+        self.apikey_auth.reload(config.apikeys.clone());
+
+        if let Some(log_filter_handle) = &self.log_filter
+            && let Err(e) = log_filter_handle.reload(log_filter)
+        {
+            error!("Failed to reload the log filter: {}", e);
+        }
+
+        self.request_timeout_milliseconds
+            .store(config.service.request_timeout_milliseconds, Ordering::Relaxed);
+
+        resize_semaphore(
+            &self.global_concurrency_semaphore,
+            max_concurrent_connections(&self.config),
+            max_concurrent_connections(&config),
+        );
+
+        self.config = config;
+    }
+}
+
+pub fn max_concurrent_connections(config: &Config) -> usize {
+    config
+        .service
+        .max_concurrent_connections
+        .unwrap_or(DEFAULT_MAX_CONCURRENT_CONNECTIONS)
+}
+
+/// Changes the total number of permits. Permits held by in-flight requests are taken away once they are released.
+fn resize_semaphore(semaphore: &Arc<Semaphore>, from: usize, to: usize) {
+    if to > from {
+        semaphore.add_permits(to - from);
+    } else if to < from {
+        let excess = from - to;
+        let forgotten = semaphore.forget_permits(excess);
+        if forgotten < excess {
+            let semaphore = semaphore.clone();
+            tokio::spawn(async move {
+                if let Ok(permits) = semaphore.acquire_many_owned((excess - forgotten) as u32).await {
+                    permits.forget();
+                }
+            });
+        }
+    }
+}
+
+/// The changed settings with their values from the redacted configs. Changed secrets are reported with both values
+/// redacted.
+fn changes(current: &Config, new: &Config) -> anyhow::Result<BTreeMap<String, (serde_json::Value, serde_json::Value)>> {
+    let mut changes = BTreeMap::new();
+    diff("", &current.redacted()?, &new.redacted()?, &mut changes);
+
+    // Changes hidden by the redaction. API keys are left out, as their paths are the keys themselves.
+    let mut unredacted = BTreeMap::new();
+    diff(
+        "",
+        &serde_json::to_value(current)?,
+        &serde_json::to_value(new)?,
+        &mut unredacted,
+    );
+    for path in unredacted.into_keys().filter(|path| !is_within(path, "apikeys")) {
+        changes
+            .entry(path)
+            .or_insert_with(|| (REDACTED.into(), REDACTED.into()));
+    }
+    if current.apikeys != new.apikeys {
+        changes
+            .entry("apikeys".to_owned())
+            .or_insert_with(|| (REDACTED.into(), REDACTED.into()));
+    }
+
+    Ok(changes)
+}
+
+fn is_within(path: &str, prefix: &str) -> bool {
+    path == prefix || path.starts_with(&format!("{prefix}."))
+}
+
+/// Collects the paths of all leaves which differ between `current` and `new`, with both values (`null` when absent).
+fn diff(
+    path: &str,
+    current: &serde_json::Value,
+    new: &serde_json::Value,
+    changes: &mut BTreeMap<String, (serde_json::Value, serde_json::Value)>,
+) {
+    match (current, new) {
+        (serde_json::Value::Object(current), serde_json::Value::Object(new)) => {
+            for key in current.keys().chain(new.keys()) {
+                let child = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{path}.{key}")
+                };
+                let null = serde_json::Value::Null;
+                diff(
+                    &child,
+                    current.get(key).unwrap_or(&null),
+                    new.get(key).unwrap_or(&null),
+                    changes,
+                );
+            }
+        }
+        _ if current != new => {
+            changes.insert(path.to_owned(), (current.clone(), new.clone()));
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn diff_reports_changed_leaves_with_both_values() {
+        let current = json!({
+            "service": {"request_timeout_milliseconds": 5000, "bind_address": "0.0.0.0:8042"},
+            "metrics": {"prefix": null, "global_labels": {"service": "shva"}},
+        });
+        let new = json!({
+            "service": {"request_timeout_milliseconds": 1000, "bind_address": "0.0.0.0:8042"},
+            "metrics": {"prefix": "shva", "global_labels": {}},
+        });
+
+        let mut changes = BTreeMap::new();
+        diff("", &current, &new, &mut changes);
+
+        assert_eq!(
+            changes,
+            BTreeMap::from([
+                ("metrics.global_labels.service".to_owned(), (json!("shva"), json!(null))),
+                ("metrics.prefix".to_owned(), (json!(null), json!("shva"))),
+                (
+                    "service.request_timeout_milliseconds".to_owned(),
+                    (json!(5000), json!(1000))
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn changed_secrets_are_reported_redacted() {
+        let config = |content: &str| -> Config {
+            toml::from_str(&format!(
+                "[service]\nbind_address = \"127.0.0.1:8042\"\nrequest_timeout_milliseconds = 1000\n{content}"
+            ))
+            .unwrap()
+        };
+        let current = config("[database]\npostgres_connection_string = \"password=a\"\n[apikeys]\nkey1 = \"user1\"");
+        let new = config("[database]\npostgres_connection_string = \"password=b\"\n[apikeys]\nkey2 = \"user1\"");
+
+        let changes = changes(&current, &new).unwrap();
+
+        let redacted = (json!(REDACTED), json!(REDACTED));
+        assert_eq!(
+            changes,
+            BTreeMap::from([
+                ("apikeys".to_owned(), redacted.clone()),
+                ("database.postgres_connection_string".to_owned(), redacted),
+            ])
+        );
+    }
+
+    #[test]
+    fn reloadable_settings_include_their_children() {
+        assert!(is_within("service.log_filter", "service.log_filter"));
+        assert!(is_within(
+            "metrics.label_limits.userid.max_values",
+            "metrics.label_limits"
+        ));
+        assert!(!is_within("service.log_filter_extra", "service.log_filter"));
+    }
+}
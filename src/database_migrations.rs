@@ -23,22 +23,82 @@
  *
  */
 
-use std::collections::{BTreeMap, HashMap};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fmt, fs,
+    io::Write,
+    path::Path,
+    time::{Duration, Instant},
+};
 
-use anyhow::anyhow;
-use tokio_postgres::NoTls;
-use tracing::error;
+use anyhow::{Context, anyhow};
+use async_trait::async_trait;
+use refinery::{AsyncMigrate, SchemaVersion, Target};
+use refinery_core::traits::r#async::{AsyncQuery, AsyncTransaction};
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{Config, DatabaseConfig, MigrationsConfig},
+    postgres_tls,
+};
 
 mod embedded {
     refinery::embed_migrations!();
 }
 
-pub fn verify_migration_versioning() -> anyhow::Result<()> {
+/// Rollback scripts, paired with the versioned migrations by version and name (`V00002__x.down.sql`).
+mod down {
+    use refinery::SchemaVersion;
+
+    include!(concat!(env!("OUT_DIR"), "/down_migrations.rs"));
+}
+
+fn down_migration(migration: &refinery::Migration) -> Option<&'static str> {
+    down::DOWN_MIGRATIONS
+        .iter()
+        .find(|&&(version, name, _)| version == migration.version() && name == migration.name())
+        .map(|&(_, _, sql)| sql)
+}
+
+/// Checks that migrations are versioned, unique and consecutive, and that every down migration belongs to a
+/// versioned migration. In `strict` mode, every versioned migration must also have a down migration.
+pub fn verify_migration_versioning(strict: bool) -> anyhow::Result<()> {
     let runner = embedded::migrations::runner();
     let migrations = runner.get_migrations();
 
     let mut is_error = false;
 
+    let orphaned: Vec<_> = down::DOWN_MIGRATIONS
+        .iter()
+        .filter(|&&(version, name, _)| {
+            !migrations
+                .iter()
+                .any(|migration| migration.version() == version && migration.name() == name)
+        })
+        .map(|(version, name, _)| format!("V{version}__{name}.down.sql"))
+        .collect();
+    if !orphaned.is_empty() {
+        is_error = true;
+        error!("Down migrations without a matching migration: `{:?}`", orphaned);
+    }
+
+    if strict {
+        let irreversible: Vec<_> = migrations
+            .iter()
+            .filter(|migration| down_migration(migration).is_none())
+            .map(|migration| migration.to_string())
+            .collect();
+        if !irreversible.is_empty() {
+            is_error = true;
+            error!(
+                "Migrations without a down migration are prohibited: `{:?}`",
+                irreversible
+            );
+        }
+    }
+
     let unversioned: Vec<_> = migrations
         .iter()
         .filter(|migration| format!("{}", migration.prefix()) != "V")
@@ -92,30 +152,477 @@ pub fn verify_migration_versioning() -> anyhow::Result<()> {
     }
 }
 
-pub(crate) async fn refinery_migrate(postgres_connection_string: &str, dryrun: bool) -> anyhow::Result<()> {
-    if !dryrun {
-        verify_migration_versioning()?;
+pub(crate) const MIGRATION_TABLE_NAME: &str = "refinery_schema_history";
+
+/// Migrations recorded in the database. Unlike `Runner::get_applied_migrations_async`, a database without the
+/// migration table (i.e. nothing was ever migrated) has no applied migrations rather than being an error.
+async fn applied_migrations(client: &mut tokio_postgres::Client) -> anyhow::Result<Vec<refinery::Migration>> {
+    let row = client
+        .query_one("SELECT to_regclass($1) IS NOT NULL", &[&MIGRATION_TABLE_NAME])
+        .await?;
+    if !row.try_get::<_, bool>(0)? {
+        return Ok(Vec::new());
     }
 
-    let (mut client, connection) = tokio_postgres::connect(postgres_connection_string, NoTls).await?;
+    Ok(embedded::migrations::runner()
+        .get_applied_migrations_async(client)
+        .await?)
+}
+
+pub(crate) async fn latest_applied_migration(client: &mut tokio_postgres::Client) -> anyhow::Result<Option<String>> {
+    Ok(applied_migrations(client)
+        .await?
+        .iter()
+        .max_by_key(|migration| migration.version())
+        .map(|migration| migration.to_string()))
+}
+
+/// Comparison of the embedded migrations with the migrations applied to the database.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct MigrationState {
+    /// Embedded but not applied.
+    pub pending: Vec<String>,
+    /// Pending, but older than the latest applied migration.
+    pub out_of_order: Vec<String>,
+    /// Applied but not embedded in this binary.
+    pub missing: Vec<String>,
+    /// The missing migrations newer than every embedded one, i.e. presumably applied by a newer release.
+    pub newer: Vec<String>,
+    /// Applied with a checksum different from the embedded migration, i.e. modified after it was applied.
+    pub mismatched: Vec<ChecksumMismatch>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ChecksumMismatch {
+    pub migration: String,
+    pub embedded_checksum: String,
+    pub applied_checksum: String,
+}
+
+impl MigrationState {
+    /// No pending, missing or modified migrations, except for migrations applied by a newer release, which a rolling
+    /// deployment leaves older instances with until they are replaced.
+    pub fn is_consistent_with_newer(&self) -> bool {
+        self.pending.is_empty() && self.missing.len() == self.newer.len() && self.mismatched.is_empty()
+    }
+
+    /// The database diverged from the embedded migrations in a way `migrate` cannot fix.
+    pub fn has_drift(&self) -> bool {
+        !self.out_of_order.is_empty() || !self.missing.is_empty() || !self.mismatched.is_empty()
+    }
+}
+
+impl fmt::Display for MigrationState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mismatched: Vec<_> = self.mismatched.iter().map(|mismatch| &mismatch.migration).collect();
+        write!(
+            f,
+            "pending: {:?}, out of order: {:?}, missing: {:?}, checksum mismatch: {:?}",
+            self.pending, self.out_of_order, self.missing, mismatched
+        )
+    }
+}
+
+fn checksum(checksum: u64) -> String {
+    format!("{checksum:#018x}")
+}
+
+pub(crate) async fn migration_state(client: &mut tokio_postgres::Client) -> anyhow::Result<MigrationState> {
+    let runner = embedded::migrations::runner();
+
+    let mut embedded: Vec<_> = runner.get_migrations().iter().collect();
+    embedded.sort_by_key(|migration| migration.version());
+    let mut applied = applied_migrations(client).await?;
+    applied.sort_by_key(|migration| migration.version());
+
+    let embedded_by_version: HashMap<_, _> = embedded
+        .iter()
+        .map(|migration| (migration.version(), migration))
+        .collect();
+    let applied_versions: HashSet<_> = applied.iter().map(|migration| migration.version()).collect();
+    let latest_applied = applied.last().map(|migration| migration.version());
+    let latest_embedded = embedded.last().map(|migration| migration.version());
+
+    let pending: Vec<_> = embedded
+        .iter()
+        .filter(|migration| !applied_versions.contains(&migration.version()))
+        .collect();
+    let mut state = MigrationState {
+        pending: pending.iter().map(|migration| migration.to_string()).collect(),
+        out_of_order: pending
+            .iter()
+            .filter(|migration| latest_applied.is_some_and(|latest| migration.version() < latest))
+            .map(|migration| migration.to_string())
+            .collect(),
+        ..Default::default()
+    };
+
+    for migration in &applied {
+        match embedded_by_version.get(&migration.version()) {
+            None => {
+                if latest_embedded.is_none_or(|latest| migration.version() > latest) {
+                    state.newer.push(migration.to_string());
+                }
+                state.missing.push(migration.to_string());
+            }
+            Some(embedded) if embedded.checksum() != migration.checksum() => state.mismatched.push(ChecksumMismatch {
+                migration: migration.to_string(),
+                embedded_checksum: checksum(embedded.checksum()),
+                applied_checksum: checksum(migration.checksum()),
+            }),
+            Some(_) => {}
+        }
+    }
+
+    Ok(state)
+}
+
+/// Lists the migrations (or, with `json`, prints the `MigrationState`) and fails on drift: migrations modified after
+/// they were applied, applied migrations missing from this binary, and pending migrations older than applied ones.
+pub(crate) async fn check_migrations(config: &Config, json: bool) -> anyhow::Result<()> {
+    let mut client = connect(&config.database).await?;
+    let state = migration_state(&mut client).await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&state)?);
+    } else {
+        migrate(&mut client, &config.migrations, true, None).await?;
+        for mismatch in &state.mismatched {
+            println!(
+                "Modified after it was applied: {} (embedded checksum {}, applied checksum {})",
+                mismatch.migration, mismatch.embedded_checksum, mismatch.applied_checksum
+            );
+        }
+        for migration in &state.missing {
+            println!("Applied but missing from this binary: {migration}");
+        }
+        for migration in &state.out_of_order {
+            println!("Pending but older than the latest applied migration: {migration}");
+        }
+    }
+
+    metrics::gauge!("migrations_drifted")
+        .set((state.mismatched.len() + state.missing.len() + state.out_of_order.len()) as f64);
+
+    verify_migration_versioning(false)?;
+
+    match state.has_drift() {
+        true => Err(anyhow!(
+            "database migrations drifted from the embedded migrations: {}",
+            state
+        )),
+        false => Ok(()),
+    }
+}
+
+/// Digits of the version in migration file names, e.g. `V00001__stats.sql`.
+const MIGRATION_VERSION_WIDTH: usize = 5;
+
+/// Creates `V<next version>__<name>.sql`, and with `down` its `.down.sql` rollback script, in `directory`. The next
+/// version follows the latest of the embedded migrations and the migration files already in `directory`.
+pub(crate) fn new_migration(directory: &Path, name: &str, down: bool) -> anyhow::Result<()> {
+    let embedded_latest = embedded::migrations::runner()
+        .get_migrations()
+        .iter()
+        .map(|migration| migration.version())
+        .max();
+    let mut file_names = Vec::new();
+    for entry in fs::read_dir(directory).with_context(|| format!("failed to read {}", directory.display()))? {
+        file_names.extend(entry?.file_name().into_string());
+    }
+    let version = next_version(embedded_latest, file_names.iter().map(String::as_str));
+
+    let stem = format!("V{version:0MIGRATION_VERSION_WIDTH$}__{name}");
+    let mut files = vec![(directory.join(format!("{stem}.sql")), format!("-- {name}\n"))];
+    if down {
+        files.push((
+            directory.join(format!("{stem}.down.sql")),
+            format!("-- Rollback of {stem}\n"),
+        ));
+    }
+
+    for (path, content) in files {
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(content.as_bytes()))
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        println!("Created {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// The version after the latest of `embedded_latest` and the versions in the migration `file_names`; other files are
+/// ignored.
+fn next_version<'a>(
+    embedded_latest: Option<SchemaVersion>,
+    file_names: impl IntoIterator<Item = &'a str>,
+) -> SchemaVersion {
+    let file_latest = file_names
+        .into_iter()
+        .filter_map(|file_name| file_name.strip_prefix('V')?.split_once("__")?.0.parse().ok())
+        .max();
+    embedded_latest.max(file_latest).unwrap_or(0) + 1
+}
+
+/// Accepts names refinery can parse from a file name: letters, digits and underscores.
+pub(crate) fn parse_migration_name(name: &str) -> Result<String, String> {
+    match !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        true => Ok(name.to_owned()),
+        false => Err(format!(
+            "invalid migration name `{name}`, use letters, digits and underscores"
+        )),
+    }
+}
+
+/// Parses a migration version given as `V<n>` or `<n>`.
+pub(crate) fn parse_version(version: &str) -> Result<SchemaVersion, String> {
+    version
+        .strip_prefix('V')
+        .unwrap_or(version)
+        .parse()
+        .map_err(|_| format!("invalid migration version `{version}`, expected e.g. `V3`"))
+}
+
+pub const DEFAULT_MIGRATION_LOCK_WAIT_TIMEOUT_SECS: u64 = 60;
+/// Session-level advisory lock serializing `migrate` and `rollback` across instances ("shvamigr").
+const MIGRATION_LOCK_ID: i64 = 0x7368_7661_6d69_6772;
+const MIGRATION_LOCK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const MIGRATION_LOCK_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Connects for migrations, with an `application_name` identifying this instance to others waiting for the lock.
+async fn connect(database_config: &DatabaseConfig) -> anyhow::Result<tokio_postgres::Client> {
+    let mut postgres_config = postgres_tls::postgres_config(database_config)?;
+    if postgres_config.get_application_name().is_none() {
+        let host = env::var("HOSTNAME").unwrap_or_else(|_| String::from("unknown"));
+        postgres_config.application_name(format!("{}-migrations@{}", env!("CARGO_PKG_NAME"), host));
+    }
+
+    let (client, connection) = postgres_config
+        .connect(postgres_tls::tls_connect(database_config)?)
+        .await?;
 
     tokio::spawn(async move {
         connection.await.unwrap();
     });
 
+    Ok(client)
+}
+
+/// Describes the sessions holding the migration lock, e.g. `pid 42 (shva-migrations@pod-1, 10.0.0.3) since ...`.
+async fn migration_lock_holder(client: &tokio_postgres::Client) -> anyhow::Result<String> {
+    let rows = client
+        .query(
+            "SELECT a.pid, a.application_name, host(a.client_addr), a.backend_start::text \
+             FROM pg_locks l JOIN pg_stat_activity a ON a.pid = l.pid \
+             WHERE l.locktype = 'advisory' AND l.granted AND l.objsubid = 1 \
+             AND ((l.classid::bigint << 32) | l.objid::bigint) = $1",
+            &[&MIGRATION_LOCK_ID],
+        )
+        .await?;
+
+    let holders: Vec<_> = rows
+        .iter()
+        .map(|row| {
+            format!(
+                "pid {} ({}, {}) connected since {}",
+                row.get::<_, i32>(0),
+                row.get::<_, Option<String>>(1).unwrap_or_default(),
+                row.get::<_, Option<String>>(2).unwrap_or_else(|| String::from("local")),
+                row.get::<_, Option<String>>(3).unwrap_or_default(),
+            )
+        })
+        .collect();
+    Ok(match holders.is_empty() {
+        true => String::from("unknown"),
+        false => holders.join(", "),
+    })
+}
+
+/// Waits up to `wait_timeout` for the migration lock, reporting who holds it meanwhile. The lock is held by the
+/// session until `release_migration_lock` or until the connection closes.
+async fn acquire_migration_lock(client: &tokio_postgres::Client, wait_timeout: Duration) -> anyhow::Result<()> {
+    let started = Instant::now();
+    let mut last_report: Option<Instant> = None;
+
+    loop {
+        let acquired: bool = client
+            .query_one("SELECT pg_try_advisory_lock($1)", &[&MIGRATION_LOCK_ID])
+            .await?
+            .get(0);
+        if acquired {
+            metrics::gauge!("migrations_lock_wait_seconds").set(started.elapsed().as_secs_f64());
+            return Ok(());
+        }
+
+        if started.elapsed() >= wait_timeout {
+            return Err(anyhow!(
+                "timed out after {:?} waiting for the migration lock held by {}",
+                wait_timeout,
+                migration_lock_holder(client).await?
+            ));
+        }
+        if last_report.is_none_or(|last_report| last_report.elapsed() >= MIGRATION_LOCK_REPORT_INTERVAL) {
+            info!(
+                "Waiting for the migration lock held by {}",
+                migration_lock_holder(client).await?
+            );
+            last_report = Some(Instant::now());
+        }
+
+        tokio::time::sleep(MIGRATION_LOCK_POLL_INTERVAL).await;
+    }
+}
+
+/// Failing to release is only logged, so that it cannot hide the outcome of the work done under the lock; the lock is
+/// released with the session anyway.
+async fn release_migration_lock(client: &tokio_postgres::Client) {
+    if let Err(e) = client
+        .execute("SELECT pg_advisory_unlock($1)", &[&MIGRATION_LOCK_ID])
+        .await
+    {
+        warn!(
+            "Failed to release the migration lock, it is held until the connection closes: {}",
+            e
+        );
+    }
+}
+
+/// Sets the configured `lock_timeout` and `statement_timeout` for the rest of the migration session.
+async fn configure_session(client: &tokio_postgres::Client, config: &MigrationsConfig) -> anyhow::Result<()> {
+    if let Some(lock_timeout) = config.lock_timeout_milliseconds {
+        client
+            .batch_execute(&format!("SET lock_timeout = {lock_timeout}"))
+            .await?;
+    }
+    if let Some(statement_timeout) = config.statement_timeout_milliseconds {
+        client
+            .batch_execute(&format!("SET statement_timeout = {statement_timeout}"))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Marks a migration which must run outside of a transaction, e.g. `CREATE INDEX CONCURRENTLY`. PostgreSQL runs a
+/// query string of several statements as one implicit transaction, so such a migration must be a single statement.
+const NO_TRANSACTION_MARKER: &str = "-- shva: no-transaction";
+
+fn is_no_transaction(migration: &refinery::Migration) -> bool {
+    migration.sql().is_some_and(has_no_transaction_marker)
+}
+
+fn has_no_transaction_marker(sql: &str) -> bool {
+    sql.lines().any(|line| line.trim() == NO_TRANSACTION_MARKER)
+}
+
+/// Runs migrations like refinery does with a plain client, in a transaction each (or all in one when grouped), except
+/// for migrations marked with `NO_TRANSACTION_MARKER`.
+struct MigrationClient<'c> {
+    client: &'c mut tokio_postgres::Client,
+}
+
+#[async_trait]
+impl AsyncTransaction for MigrationClient<'_> {
+    type Error = tokio_postgres::Error;
+
+    async fn execute<'a, T: Iterator<Item = &'a str> + Send>(&mut self, queries: T) -> Result<usize, Self::Error> {
+        let queries: Vec<&str> = queries.collect();
+        match queries.first() {
+            // The migration, then its history row. If recording fails, the migration is applied but still pending.
+            Some(&migration) if has_no_transaction_marker(migration) => {
+                for query in &queries {
+                    self.client.batch_execute(query).await?;
+                }
+                Ok(queries.len())
+            }
+            _ => AsyncTransaction::execute(&mut *self.client, queries.into_iter()).await,
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncQuery<Vec<refinery::Migration>> for MigrationClient<'_> {
+    async fn query(&mut self, query: &str) -> Result<Vec<refinery::Migration>, Self::Error> {
+        AsyncQuery::query(&mut *self.client, query).await
+    }
+}
+
+impl AsyncMigrate for MigrationClient<'_> {}
+
+fn migration_lock_wait_timeout(config: &Config) -> Duration {
+    Duration::from_secs(
+        config
+            .migrations
+            .lock_wait_timeout_secs
+            .unwrap_or(DEFAULT_MIGRATION_LOCK_WAIT_TIMEOUT_SECS),
+    )
+}
+
+/// Lists the migrations and, unless `dryrun`, applies the pending ones up to `target` (all when `None`) while
+/// holding the migration lock, so that concurrently deployed instances don't race.
+pub(crate) async fn refinery_migrate(
+    config: &Config,
+    dryrun: bool,
+    target: Option<SchemaVersion>,
+) -> anyhow::Result<()> {
+    if dryrun {
+        let mut client = connect(&config.database).await?;
+        migrate(&mut client, &config.migrations, true, target).await?;
+        return verify_migration_versioning(false);
+    }
+
+    verify_migration_versioning(false)?;
+
+    let mut client = connect(&config.database).await?;
+    acquire_migration_lock(&client, migration_lock_wait_timeout(config)).await?;
+    let result = async {
+        configure_session(&client, &config.migrations).await?;
+        migrate(&mut client, &config.migrations, false, target).await
+    }
+    .await;
+    release_migration_lock(&client).await;
+    result
+}
+
+async fn migrate(
+    client: &mut tokio_postgres::Client,
+    config: &MigrationsConfig,
+    dryrun: bool,
+    target: Option<SchemaVersion>,
+) -> anyhow::Result<()> {
     let runner = embedded::migrations::runner();
 
-    let mut applied_migrations = runner
-        .get_applied_migrations_async(&mut client)
+    let mut applied_migrations = applied_migrations(client)
         .await?
         .into_iter()
         .map(|migration| (migration.name().to_owned(), migration.applied_on().cloned()))
         .collect::<HashMap<String, _>>();
 
-    println!("Applied migrations:");
-
     let mut migrations: Vec<&refinery::Migration> = runner.get_migrations().iter().collect();
     migrations.sort_by_key(|a| a.version());
+
+    if let Some(target) = target
+        && !migrations.iter().any(|migration| migration.version() == target)
+    {
+        return Err(anyhow!("target V{} is not an embedded migration", target));
+    }
+
+    let pending: Vec<_> = migrations
+        .iter()
+        .filter(|migration| !applied_migrations.contains_key(migration.name()))
+        .collect();
+    metrics::gauge!("migrations_embedded").set(migrations.len() as f64);
+    metrics::gauge!("migrations_pending").set(pending.len() as f64);
+
+    let planned: Vec<_> = pending
+        .iter()
+        .filter(|migration| target.is_none_or(|target| migration.version() <= target))
+        .collect();
+
+    println!("Applied migrations:");
+
     migrations.iter().for_each(|migration| {
         let applied_on = applied_migrations
             .remove(migration.name())
@@ -132,13 +639,226 @@ pub(crate) async fn refinery_migrate(postgres_connection_string: &str, dryrun: b
         );
     });
 
+    match target {
+        Some(target) => println!("Migrations to apply, in order, up to V{target}:"),
+        None => println!("Migrations to apply, in order:"),
+    }
+    if planned.is_empty() {
+        println!("none");
+    }
+    for migration in &planned {
+        match is_no_transaction(migration) {
+            true => println!("{} | {:#016x} | no transaction", migration, migration.checksum()),
+            false => println!("{} | {:#016x}", migration, migration.checksum()),
+        }
+    }
+
+    let grouped = config.grouped.unwrap_or(false);
+    if grouped && let Some(migration) = planned.iter().find(|migration| is_no_transaction(migration)) {
+        return Err(anyhow!(
+            "{} is marked `{}` and cannot be applied with migrations.grouped",
+            migration,
+            NO_TRANSACTION_MARKER
+        ));
+    }
+
     if !dryrun {
         println!("Running migrations");
-        runner.run_async(&mut client).await?;
+        let runner = embedded::migrations::runner()
+            .set_grouped(grouped)
+            .set_target(match target {
+                Some(target) => Target::Version(target),
+                None => Target::Latest,
+            });
+        let started = Instant::now();
+        let report = runner.run_async(&mut MigrationClient { client }).await?;
+        metrics::gauge!("migrations_run_duration_seconds").set(started.elapsed().as_secs_f64());
+        metrics::counter!("migrations_applied_total").increment(report.applied_migrations().len() as u64);
+        metrics::gauge!("migrations_pending")
+            .set(pending.len().saturating_sub(report.applied_migrations().len()) as f64);
         println!("Success!");
-    } else {
-        verify_migration_versioning()?;
     }
 
     Ok(())
 }
+
+/// Applies all embedded migrations to a scratch database, without listing them or taking the migration lock.
+pub(crate) async fn migrate_scratch(client: &mut tokio_postgres::Client) -> anyhow::Result<()> {
+    embedded::migrations::runner()
+        .run_async(&mut MigrationClient { client })
+        .await?;
+    Ok(())
+}
+
+/// Applies the down migrations of every migration applied after `to`, newest first, in a single transaction, while
+/// holding the migration lock.
+pub(crate) async fn rollback(config: &Config, to: SchemaVersion) -> anyhow::Result<()> {
+    let mut client = connect(&config.database).await?;
+    acquire_migration_lock(&client, migration_lock_wait_timeout(config)).await?;
+    let result = async {
+        configure_session(&client, &config.migrations).await?;
+        roll_back(&mut client, to).await
+    }
+    .await;
+    release_migration_lock(&client).await;
+    result
+}
+
+async fn roll_back(client: &mut tokio_postgres::Client, to: SchemaVersion) -> anyhow::Result<()> {
+    let mut applied = applied_migrations(client).await?;
+    applied.retain(|migration| migration.version() > to);
+    applied.sort_by_key(|migration| Reverse(migration.version()));
+
+    if applied.is_empty() {
+        println!("Nothing to roll back: no migrations applied after V{to}");
+        return Ok(());
+    }
+
+    let down_migrations = applied
+        .iter()
+        .map(|migration| {
+            down_migration(migration)
+                .map(|sql| (migration, sql))
+                .ok_or_else(|| anyhow!("{} has no down migration", migration))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let transaction = client.transaction().await?;
+    for (migration, sql) in down_migrations {
+        println!("Rolling back {}", migration);
+        transaction
+            .batch_execute(sql)
+            .await
+            .with_context(|| format!("failed to roll back {migration}"))?;
+        transaction
+            .execute(
+                &format!("DELETE FROM {MIGRATION_TABLE_NAME} WHERE version = $1"),
+                &[&migration.version()],
+            )
+            .await?;
+    }
+    transaction.commit().await?;
+
+    metrics::counter!("migrations_rolled_back_total").increment(applied.len() as u64);
+    println!("Success!");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn numbers_after_the_latest_migration() {
+        assert_eq!(next_version(None, []), 1);
+        assert_eq!(next_version(Some(3), []), 4);
+        assert_eq!(
+            next_version(Some(3), ["V00002__a.sql", "V00007__b.sql", "V00007__b.down.sql"]),
+            8
+        );
+        assert_eq!(next_version(Some(9), ["V00002__a.sql"]), 10);
+        // Unversioned and unrelated files, and versions without a name, don't count.
+        assert_eq!(
+            next_version(Some(1), ["U00005__a.sql", "README.md", "V00009.sql", "Vx__a.sql"]),
+            2
+        );
+    }
+
+    #[test]
+    fn creates_migration_files() {
+        let directory = std::env::temp_dir().join(format!("shva-new-migration-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let latest = embedded::migrations::runner()
+            .get_migrations()
+            .iter()
+            .map(|migration| migration.version())
+            .max()
+            .unwrap_or(0);
+        fs::write(directory.join(format!("V{:05}__local.sql", latest + 1)), "").unwrap();
+
+        new_migration(&directory, "next", true).unwrap();
+
+        let stem = format!("V{:05}__next", latest + 2);
+        assert_eq!(
+            fs::read_to_string(directory.join(format!("{stem}.sql"))).unwrap(),
+            "-- next\n"
+        );
+        assert!(directory.join(format!("{stem}.down.sql")).exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn parses_migration_names() {
+        assert_eq!(parse_migration_name("add_users_2"), Ok("add_users_2".to_owned()));
+        assert!(parse_migration_name("").is_err());
+        assert!(parse_migration_name("add-users").is_err());
+        assert!(parse_migration_name("add users").is_err());
+    }
+
+    #[test]
+    fn parses_versions() {
+        assert_eq!(parse_version("V3"), Ok(3));
+        assert_eq!(parse_version("3"), Ok(3));
+        assert!(parse_version("v3").is_err());
+        assert!(parse_version("V").is_err());
+        assert!(parse_version("V00001__stats").is_err());
+    }
+
+    #[test]
+    fn finds_the_no_transaction_marker_on_its_own_line() {
+        assert!(has_no_transaction_marker(
+            "-- shva: no-transaction\nCREATE INDEX CONCURRENTLY stats_idx ON stats (id);\n"
+        ));
+        assert!(has_no_transaction_marker(
+            "-- Builds the index.\n  -- shva: no-transaction  \nSELECT 1;"
+        ));
+        assert!(!has_no_transaction_marker("CREATE INDEX stats_idx ON stats (id);"));
+        assert!(!has_no_transaction_marker(
+            "-- shva: no-transaction, but not really\nSELECT 1;"
+        ));
+        assert!(!has_no_transaction_marker("SELECT 1; -- shva: no-transaction"));
+        assert!(!has_no_transaction_marker("-- SHVA: NO-TRANSACTION\nSELECT 1;"));
+    }
+
+    #[test]
+    fn marks_migrations_as_no_transaction() {
+        let marked = refinery::Migration::unapplied(
+            "V2__index",
+            "-- shva: no-transaction\nCREATE INDEX CONCURRENTLY stats_idx ON stats (id);",
+        )
+        .unwrap();
+        let unmarked = refinery::Migration::unapplied("V3__table", "CREATE TABLE t (id int);").unwrap();
+
+        assert!(is_no_transaction(&marked));
+        assert!(!is_no_transaction(&unmarked));
+    }
+
+    #[test]
+    fn tolerates_only_newer_migrations() {
+        let newer = MigrationState {
+            missing: names(&["V2__future"]),
+            newer: names(&["V2__future"]),
+            ..Default::default()
+        };
+        assert!(newer.is_consistent_with_newer());
+        assert!(newer.has_drift());
+
+        let removed = MigrationState {
+            missing: names(&["V1__removed", "V3__future"]),
+            newer: names(&["V3__future"]),
+            ..Default::default()
+        };
+        assert!(!removed.is_consistent_with_newer());
+
+        let pending = MigrationState {
+            pending: names(&["V2__next"]),
+            ..Default::default()
+        };
+        assert!(!pending.is_consistent_with_newer());
+    }
+}
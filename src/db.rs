@@ -23,16 +23,65 @@
  *
  */
 
-use std::time::Duration;
+use std::{pin::Pin, time::Duration};
 
-use bb8::Pool;
+use bb8::{CustomizeConnection, Pool};
 use bb8_postgres::PostgresConnectionManager;
-use tokio_postgres::NoTls;
+use tokio_postgres_rustls::MakeRustlsConnect;
 use tracing::{Level, event, info, instrument};
 
-use crate::config::DatabaseConfig;
+use crate::{config::DatabaseConfig, postgres_tls};
 
-pub type ConnectionPool = Pool<PostgresConnectionManager<NoTls>>;
+pub type ConnectionPool = Pool<PostgresConnectionManager<MakeRustlsConnect>>;
+
+pub const DEFAULT_POOL_MAX_SIZE: u32 = 10;
+pub const DEFAULT_POOL_IDLE_TIMEOUT_SECS: u64 = 600;
+pub const DEFAULT_POOL_MAX_LIFETIME_SECS: u64 = 1800;
+
+pub fn pool_max_size(database_config: &DatabaseConfig) -> u32 {
+    database_config.pool_max_size.unwrap_or(DEFAULT_POOL_MAX_SIZE)
+}
+
+/// Session settings applied to every new pool connection.
+#[derive(Debug)]
+struct SessionSettings(Vec<(&'static str, String)>);
+
+impl SessionSettings {
+    fn new(database_config: &DatabaseConfig) -> Self {
+        let settings = [
+            ("application_name", database_config.application_name.clone()),
+            (
+                "statement_timeout",
+                database_config
+                    .statement_timeout_milliseconds
+                    .map(|statement_timeout| statement_timeout.to_string()),
+            ),
+            ("search_path", database_config.search_path.clone()),
+        ];
+        Self(
+            settings
+                .into_iter()
+                .filter_map(|(name, value)| value.map(|value| (name, value)))
+                .collect(),
+        )
+    }
+}
+
+impl CustomizeConnection<tokio_postgres::Client, tokio_postgres::Error> for SessionSettings {
+    fn on_acquire<'a>(
+        &'a self,
+        connection: &'a mut tokio_postgres::Client,
+    ) -> Pin<Box<dyn Future<Output = Result<(), tokio_postgres::Error>> + Send + 'a>> {
+        Box::pin(async move {
+            for (name, value) in &self.0 {
+                connection
+                    .execute("SELECT set_config($1, $2, false)", &[name, value])
+                    .await?;
+            }
+            Ok(())
+        })
+    }
+}
 
 pub fn update_metric_gauges(pool: &ConnectionPool) {
     let pool_state = pool.state();
@@ -42,14 +91,40 @@ pub fn update_metric_gauges(pool: &ConnectionPool) {
 }
 
 pub async fn setup_pool(database_config: &DatabaseConfig) -> anyhow::Result<ConnectionPool> {
-    let manager = PostgresConnectionManager::new_from_stringlike(&database_config.postgres_connection_string, NoTls)?;
-
-    let mut pool_builder = Pool::builder();
+    let manager = PostgresConnectionManager::new(
+        postgres_tls::postgres_config(database_config)?,
+        postgres_tls::tls_connect(database_config)?,
+    );
+
+    let mut pool_builder = Pool::builder()
+        .max_size(pool_max_size(database_config))
+        .min_idle(database_config.pool_min_idle)
+        .idle_timeout(Duration::from_secs(
+            database_config
+                .pool_idle_timeout_secs
+                .unwrap_or(DEFAULT_POOL_IDLE_TIMEOUT_SECS),
+        ))
+        .max_lifetime(Duration::from_secs(
+            database_config
+                .pool_max_lifetime_secs
+                .unwrap_or(DEFAULT_POOL_MAX_LIFETIME_SECS),
+        ))
+        .test_on_check_out(database_config.pool_test_on_checkout.unwrap_or(true))
+        .connection_customizer(Box::new(SessionSettings::new(database_config)));
     if let Some(connection_timeout) = database_config.connection_timeout_secs {
         pool_builder = pool_builder.connection_timeout(Duration::from_secs(connection_timeout));
     }
 
+    // Waits until the `min_idle` connections are open, so the service only starts (and becomes ready) with a warm
+    // pool.
     let pool: ConnectionPool = pool_builder.build(manager).await?;
+    if let Some(min_idle) = database_config.pool_min_idle {
+        info!(
+            "Startup check: database pool warmed up with {} of {} idle connections",
+            pool.state().idle_connections,
+            min_idle
+        );
+    }
 
     info!("Startup check: pinging database");
     crate::db::ping(pool.clone()).await?;
@@ -0,0 +1,496 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2022 Eldad Zack
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ */
+
+//! Readiness checks.
+//!
+//! Checks implement [`HealthCheck`] and are registered in [`HealthChecks`], which runs them concurrently, each with
+//! its own timeout, and aggregates the results into a [`HealthReport`].
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use axum::{Json, extract::Extension, http::StatusCode};
+use serde::Serialize;
+use tokio::{
+    net::TcpStream,
+    task::JoinSet,
+    time::{Instant, timeout},
+};
+
+use crate::{database_migrations::MigrationState, db::ConnectionPool};
+
+#[async_trait]
+pub trait HealthCheck: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// A failing critical check fails readiness; a failing non-critical check is only reported.
+    fn critical(&self) -> bool {
+        true
+    }
+
+    async fn check(&self) -> anyhow::Result<()>;
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CheckReport {
+    pub name: &'static str,
+    pub status: HealthStatus,
+    pub critical: bool,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub checks: Vec<CheckReport>,
+}
+
+pub struct HealthChecks {
+    checks: Vec<Arc<dyn HealthCheck>>,
+    timeout: Duration,
+}
+
+impl HealthChecks {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            checks: Vec::new(),
+            timeout,
+        }
+    }
+
+    pub fn register(mut self, check: impl HealthCheck + 'static) -> Self {
+        self.checks.push(Arc::new(check));
+        self
+    }
+
+    pub async fn run(&self) -> HealthReport {
+        let mut tasks = JoinSet::new();
+
+        for (index, check) in self.checks.iter().cloned().enumerate() {
+            let check_timeout = self.timeout;
+            tasks.spawn(async move {
+                let started = Instant::now();
+                let result = match timeout(check_timeout, check.check()).await {
+                    Ok(result) => result,
+                    Err(_) => Err(anyhow!("timed out after {:?}", check_timeout)),
+                };
+                let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+                let status = match (&result, check.critical()) {
+                    (Ok(_), _) => HealthStatus::Pass,
+                    (Err(_), true) => HealthStatus::Fail,
+                    (Err(_), false) => HealthStatus::Warn,
+                };
+
+                (
+                    index,
+                    CheckReport {
+                        name: check.name(),
+                        status,
+                        critical: check.critical(),
+                        latency_ms,
+                        error: result.err().map(|err| format!("{err:#}")),
+                    },
+                )
+            });
+        }
+
+        let mut checks: Vec<_> = tasks.join_all().await;
+        checks.sort_by_key(|(index, _)| *index);
+        let checks: Vec<_> = checks.into_iter().map(|(_, report)| report).collect();
+
+        let status = checks
+            .iter()
+            .map(|check| check.status)
+            .max_by_key(|status| match status {
+                HealthStatus::Pass => 0,
+                HealthStatus::Warn => 1,
+                HealthStatus::Fail => 2,
+            })
+            .unwrap_or(HealthStatus::Pass);
+
+        HealthReport { status, checks }
+    }
+}
+
+#[utoipa::path(get, path = "/monitoring/readiness", responses(
+    (status = 200, description = "service is ready; the body reports every check"),
+    (status = 503, description = "a critical check failed; the body reports every check")
+))]
+pub async fn readiness(Extension(health_checks): Extension<Arc<HealthChecks>>) -> (StatusCode, Json<HealthReport>) {
+    report_response(health_checks.run().await)
+}
+
+fn report_response(report: HealthReport) -> (StatusCode, Json<HealthReport>) {
+    let status_code = match report.status {
+        HealthStatus::Fail => StatusCode::SERVICE_UNAVAILABLE,
+        HealthStatus::Pass | HealthStatus::Warn => StatusCode::OK,
+    };
+
+    (status_code, Json(report))
+}
+
+/// Latches once all startup checks have passed, after which they are no longer run.
+pub struct StartupProbe {
+    checks: HealthChecks,
+    started: AtomicBool,
+}
+
+impl StartupProbe {
+    pub fn new(checks: HealthChecks) -> Self {
+        Self {
+            checks,
+            started: AtomicBool::new(false),
+        }
+    }
+
+    pub async fn run(&self) -> HealthReport {
+        if self.started.load(Ordering::Relaxed) {
+            return HealthReport {
+                status: HealthStatus::Pass,
+                checks: Vec::new(),
+            };
+        }
+
+        let report = self.checks.run().await;
+        if report.status != HealthStatus::Fail {
+            self.started.store(true, Ordering::Relaxed);
+        }
+        report
+    }
+}
+
+#[utoipa::path(get, path = "/monitoring/startup", responses(
+    (status = 200, description = "service has started"),
+    (status = 503, description = "a startup check failed; the body reports every check")
+))]
+pub async fn startup(Extension(startup_probe): Extension<Arc<StartupProbe>>) -> (StatusCode, Json<HealthReport>) {
+    report_response(startup_probe.run().await)
+}
+
+/// Fails once a shutdown signal has been received, so that no new traffic is routed to the instance while it drains.
+pub struct ShutdownCheck(pub Arc<AtomicBool>);
+
+#[async_trait]
+impl HealthCheck for ShutdownCheck {
+    fn name(&self) -> &'static str {
+        "shutdown"
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        match self.0.load(Ordering::Relaxed) {
+            true => Err(anyhow!("shutting down")),
+            false => Ok(()),
+        }
+    }
+}
+
+pub struct DatabaseCheck(pub ConnectionPool);
+
+#[async_trait]
+impl HealthCheck for DatabaseCheck {
+    fn name(&self) -> &'static str {
+        "database"
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        crate::db::ping(self.0.clone()).await
+    }
+}
+
+/// Warns when every pool connection is in use, i.e. the next request has to wait for a connection.
+pub struct PoolCapacityCheck {
+    pub pool: ConnectionPool,
+    pub max_size: u32,
+}
+
+#[async_trait]
+impl HealthCheck for PoolCapacityCheck {
+    fn name(&self) -> &'static str {
+        "database_pool_capacity"
+    }
+
+    fn critical(&self) -> bool {
+        false
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        let state = self.pool.state();
+        let free = self.max_size.saturating_sub(state.connections) + state.idle_connections;
+
+        match free {
+            0 => Err(anyhow!("all {} pool connections are in use", self.max_size)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How long the migration state is reused before the schema history is queried again.
+const MIGRATION_STATE_TTL: Duration = Duration::from_secs(30);
+
+/// The migration state, queried at most once per `MIGRATION_STATE_TTL` however often it is probed.
+pub struct MigrationStateCache {
+    pool: ConnectionPool,
+    cached: tokio::sync::Mutex<Option<(Instant, Arc<MigrationState>)>>,
+}
+
+impl MigrationStateCache {
+    pub fn new(pool: ConnectionPool) -> Self {
+        Self {
+            pool,
+            cached: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    async fn get(&self) -> anyhow::Result<Arc<MigrationState>> {
+        // Held while querying, so that concurrent probes share a single query.
+        let mut cached = self.cached.lock().await;
+        if let Some((queried, state)) = &*cached
+            && queried.elapsed() < MIGRATION_STATE_TTL
+        {
+            return Ok(state.clone());
+        }
+
+        let mut conn = self.pool.get().await?;
+        let state = Arc::new(crate::database_migrations::migration_state(&mut conn).await?);
+        *cached = Some((Instant::now(), state.clone()));
+        Ok(state)
+    }
+}
+
+/// Fails when the database schema is not in sync with the embedded migrations: pending, missing or modified
+/// migrations. Migrations applied by a newer release are left to [`NewerMigrationsCheck`], so that a rolling
+/// deployment migrating the database does not take the instances it is replacing out of service, nor keeps them
+/// from restarting.
+pub struct MigrationStateCheck(pub Arc<MigrationStateCache>);
+
+#[async_trait]
+impl HealthCheck for MigrationStateCheck {
+    fn name(&self) -> &'static str {
+        "migrations"
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        let state = self.0.get().await?;
+
+        match state.is_consistent_with_newer() {
+            true => Ok(()),
+            false => Err(anyhow!("{}", state)),
+        }
+    }
+}
+
+/// Warns about migrations applied by a newer release: this instance is outdated.
+pub struct NewerMigrationsCheck(pub Arc<MigrationStateCache>);
+
+#[async_trait]
+impl HealthCheck for NewerMigrationsCheck {
+    fn name(&self) -> &'static str {
+        "newer_migrations"
+    }
+
+    fn critical(&self) -> bool {
+        false
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        let state = self.0.get().await?;
+
+        match state.newer.is_empty() {
+            true => Ok(()),
+            false => Err(anyhow!("applied by a newer release: {:?}", state.newer)),
+        }
+    }
+}
+
+/// Checks that the OTLP trace exporter endpoint accepts connections.
+pub struct TracingExporterCheck {
+    address: String,
+}
+
+impl TracingExporterCheck {
+    const DEFAULT_ENDPOINT: &str = "http://localhost:4318";
+
+    /// Uses the endpoint the exporter is configured with (`OTEL_EXPORTER_OTLP_ENDPOINT`).
+    pub fn from_env() -> anyhow::Result<Self> {
+        let endpoint = std::env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT")
+            .or_else(|_| std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT"))
+            .unwrap_or_else(|_| Self::DEFAULT_ENDPOINT.to_owned());
+        Self::from_endpoint(&endpoint)
+    }
+
+    fn from_endpoint(endpoint: &str) -> anyhow::Result<Self> {
+        let uri: http::Uri = endpoint.parse()?;
+        let host = uri
+            .host()
+            .ok_or_else(|| anyhow!("OTLP endpoint `{}` has no host", endpoint))?;
+        let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+            Some("https") => 443,
+            _ => 80,
+        });
+
+        Ok(Self {
+            address: format!("{host}:{port}"),
+        })
+    }
+}
+
+#[async_trait]
+impl HealthCheck for TracingExporterCheck {
+    fn name(&self) -> &'static str {
+        "tracing_exporter"
+    }
+
+    fn critical(&self) -> bool {
+        false
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+        TcpStream::connect(&self.address).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct StubCheck {
+        name: &'static str,
+        critical: bool,
+        result: fn() -> anyhow::Result<()>,
+        delay: Duration,
+    }
+
+    impl StubCheck {
+        fn new(name: &'static str, critical: bool, result: fn() -> anyhow::Result<()>) -> Self {
+            Self {
+                name,
+                critical,
+                result,
+                delay: Duration::ZERO,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl HealthCheck for StubCheck {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn critical(&self) -> bool {
+            self.critical
+        }
+
+        async fn check(&self) -> anyhow::Result<()> {
+            tokio::time::sleep(self.delay).await;
+            (self.result)()
+        }
+    }
+
+    fn pass() -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn fail() -> anyhow::Result<()> {
+        Err(anyhow!("broken"))
+    }
+
+    #[tokio::test]
+    async fn failing_critical_check_fails_the_report() {
+        let report = HealthChecks::new(Duration::from_secs(1))
+            .register(StubCheck::new("a", true, pass))
+            .register(StubCheck::new("b", false, fail))
+            .register(StubCheck::new("c", true, fail))
+            .run()
+            .await;
+
+        assert_eq!(report.status, HealthStatus::Fail);
+        let statuses: Vec<_> = report.checks.iter().map(|check| (check.name, check.status)).collect();
+        assert_eq!(
+            statuses,
+            [
+                ("a", HealthStatus::Pass),
+                ("b", HealthStatus::Warn),
+                ("c", HealthStatus::Fail)
+            ]
+        );
+        assert_eq!(report.checks[2].error.as_deref(), Some("broken"));
+    }
+
+    #[tokio::test]
+    async fn failing_non_critical_check_only_warns() {
+        let report = HealthChecks::new(Duration::from_secs(1))
+            .register(StubCheck::new("a", true, pass))
+            .register(StubCheck::new("b", false, fail))
+            .run()
+            .await;
+
+        assert_eq!(report.status, HealthStatus::Warn);
+        assert_eq!(report_response(report).0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn slow_checks_time_out() {
+        let report = HealthChecks::new(Duration::from_millis(50))
+            .register(StubCheck {
+                delay: Duration::from_secs(10),
+                ..StubCheck::new("slow", true, pass)
+            })
+            .run()
+            .await;
+
+        assert_eq!(report.status, HealthStatus::Fail);
+        assert_eq!(report.checks[0].error.as_deref(), Some("timed out after 50ms"));
+    }
+
+    #[test]
+    fn tracing_exporter_address_defaults_the_port_by_scheme() {
+        let address = |endpoint| TracingExporterCheck::from_endpoint(endpoint).map(|check| check.address);
+        assert_eq!(address("http://collector:4318").unwrap(), "collector:4318");
+        assert_eq!(address("https://collector").unwrap(), "collector:443");
+        assert_eq!(address("http://collector/v1/traces").unwrap(), "collector:80");
+        assert!(address("not a uri").is_err());
+        assert!(address("/v1/traces").is_err());
+    }
+}
@@ -75,11 +75,6 @@ pub async fn simulate_query_long(Extension(pool): Extension<ConnectionPool>) ->
     Ok(StatusCode::NO_CONTENT)
 }
 
-pub async fn database_ping(Extension(pool): Extension<ConnectionPool>) -> Result<StatusCode, AppError> {
-    crate::db::ping(pool).await?;
-    Ok(StatusCode::NO_CONTENT)
-}
-
 #[derive(Serialize, Deserialize)]
 pub struct MessageEntity {
     pub id: usize,
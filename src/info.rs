@@ -0,0 +1,124 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2022 Eldad Zack
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ */
+
+//! Build and runtime information about the running instance.
+
+use std::{
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{Json, extract::Extension};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::db::ConnectionPool;
+
+/// Captured in `build.rs`.
+const GIT_COMMIT: &str = env!("SHVA_GIT_COMMIT");
+const BUILD_TIMESTAMP_SECONDS: &str = env!("SHVA_BUILD_TIMESTAMP");
+const RUSTC_VERSION: &str = env!("SHVA_RUSTC_VERSION");
+const CARGO_FEATURES: &str = env!("SHVA_CARGO_FEATURES");
+
+pub struct ServiceInfo {
+    start_time: SystemTime,
+    started: Instant,
+    config: serde_json::Value,
+}
+
+impl ServiceInfo {
+    /// `config` is expected to be redacted already.
+    pub fn new(config: serde_json::Value) -> Self {
+        Self {
+            start_time: SystemTime::now(),
+            started: Instant::now(),
+            config,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct InfoResponse {
+    version: &'static str,
+    git_commit: &'static str,
+    build_timestamp_seconds: u64,
+    rustc_version: &'static str,
+    cargo_features: Vec<&'static str>,
+    start_time_seconds: f64,
+    uptime_seconds: f64,
+    latest_applied_migration: Option<String>,
+    config: serde_json::Value,
+}
+
+/// Sets the `build_info` gauge, whose labels carry the build information.
+pub fn register_build_info_metric() {
+    metrics::gauge!(
+        "build_info",
+        "version" => env!("CARGO_PKG_VERSION"),
+        "git_commit" => GIT_COMMIT,
+        "rustc_version" => RUSTC_VERSION,
+    )
+    .set(1.0);
+}
+
+async fn latest_applied_migration(pool: &ConnectionPool) -> anyhow::Result<Option<String>> {
+    let mut conn = pool.get().await?;
+    crate::database_migrations::latest_applied_migration(&mut conn).await
+}
+
+/// Served on the metrics listener when there is one, otherwise on the service port behind API key authentication,
+/// as the config and user ids are not for everyone's eyes.
+#[utoipa::path(get, path = "/monitoring/info", responses(
+    (status = 200, description = "build and runtime information; `latest_applied_migration` is null when the database is unavailable")
+))]
+pub async fn info(
+    Extension(service_info): Extension<Arc<ServiceInfo>>,
+    Extension(pool): Extension<ConnectionPool>,
+) -> Json<InfoResponse> {
+    // The rest of the information is still worth answering with while the database is down.
+    let latest_applied_migration = latest_applied_migration(&pool).await.unwrap_or_else(|e| {
+        warn!("Failed to read the latest applied migration: {:#}", e);
+        None
+    });
+
+    Json(InfoResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: GIT_COMMIT,
+        build_timestamp_seconds: BUILD_TIMESTAMP_SECONDS.parse().unwrap_or_default(),
+        rustc_version: RUSTC_VERSION,
+        cargo_features: CARGO_FEATURES
+            .split(',')
+            .filter(|feature| !feature.is_empty())
+            .collect(),
+        start_time_seconds: service_info
+            .start_time
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs_f64())
+            .unwrap_or_default(),
+        uptime_seconds: service_info.started.elapsed().as_secs_f64(),
+        latest_applied_migration,
+        config: service_info.config.clone(),
+    })
+}
@@ -29,15 +29,32 @@ mod apikey_auth;
 mod apperror;
 mod appmetrics;
 mod apptracing;
+mod cli;
 mod config;
+mod config_reload;
 mod database_migrations;
 mod db;
+mod health;
 mod http_methods;
+mod info;
+mod metrics_push;
+mod openmetrics;
+mod postgres_tls;
+mod runtime_metrics;
+mod schema_snapshot;
 mod shutdown_signal;
 
 mod cbor;
 
-use std::{sync::Arc, time::Duration};
+use std::{
+    future::IntoFuture,
+    process::ExitCode,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64},
+    },
+    time::Duration,
+};
 
 use anyhow::anyhow;
 use axum::{
@@ -54,23 +71,29 @@ use tower::{
     ServiceBuilder,
     limit::GlobalConcurrencyLimitLayer,
     load_shed::{LoadShedLayer, error::Overloaded},
-    timeout::{TimeoutLayer, error::Elapsed},
+    timeout::error::Elapsed,
 };
 use tower_http::{
     classify::StatusInRangeAsFailures, compression::CompressionLayer, trace::TraceLayer,
     validate_request::ValidateRequestHeaderLayer,
 };
-use tracing::{Level, debug, error, event, info};
+use tracing::{Level, debug, error, event, info, warn};
 
-use crate::config::Config;
+use crate::{
+    cli::Command,
+    config::{Config, MetricsConfig},
+};
 
 const SERVICE_NAME: &str = env!("CARGO_PKG_NAME");
 const DEFAULT_MAX_CONCURRENT_CONNECTIONS: usize = 3;
+const DEFAULT_HEALTH_CHECK_TIMEOUT_MILLISECONDS: u64 = 1_000;
+const DEFAULT_SHUTDOWN_PRE_STOP_DELAY_MILLISECONDS: u64 = 0;
+const DEFAULT_SHUTDOWN_DRAIN_TIMEOUT_MILLISECONDS: u64 = 30_000;
 
 use utoipa::OpenApi;
 
 #[derive(OpenApi)]
-#[openapi(paths(http_methods::liveness))]
+#[openapi(paths(http_methods::liveness, health::startup, health::readiness, info::info))]
 struct ApiDoc;
 
 async fn handle_error(method: Method, uri: Uri, error: BoxError) -> impl IntoResponse {
@@ -86,22 +109,132 @@ async fn handle_error(method: Method, uri: Uri, error: BoxError) -> impl IntoRes
     }
 }
 
-async fn service(config: Config) -> anyhow::Result<()> {
+async fn service(config: Config, config_path: String, log_filter: apptracing::LogFilterHandle) -> anyhow::Result<()> {
+    let service_info = Arc::new(info::ServiceInfo::new(config.redacted()?));
     let db_pool = crate::db::setup_pool(&config.database).await?;
-    let prometheus_handle = Arc::new(appmetrics::install_prometheus()?);
-    let global_concurrency_semapshore = Arc::new(Semaphore::new(
+    let prometheus_handle = Arc::new(appmetrics::install_prometheus(&config.metrics)?);
+    info::register_build_info_metric();
+
+    if config.migrations.run_on_startup.unwrap_or(false) {
+        info!("Applying pending migrations at startup");
+        database_migrations::refinery_migrate(&config, false, None).await?;
+    }
+
+    info!("Startup check: comparing embedded migrations with the database");
+    let migration_state = database_migrations::migration_state(&mut *db_pool.get().await?).await?;
+    if !migration_state.is_consistent_with_newer() {
+        error!("Database migrations are not in sync: {}", migration_state);
+        if config.service.exit_on_inconsistent_migrations.unwrap_or(false) {
+            return Err(anyhow!("database migrations are not in sync: {}", migration_state));
+        }
+    } else if !migration_state.newer.is_empty() {
+        warn!(
+            "Database has migrations applied by a newer release, this instance is outdated: {:?}",
+            migration_state.newer
+        );
+    }
+
+    let label_guards = Arc::new(appmetrics::LabelGuards::from_config(&config.metrics));
+    let exemplars = Arc::new(appmetrics::request_duration_exemplars(&config.metrics));
+    let draining = Arc::new(AtomicBool::new(false));
+    let health_check_timeout = Duration::from_millis(
         config
             .service
-            .max_concurrent_connections
-            .unwrap_or(DEFAULT_MAX_CONCURRENT_CONNECTIONS),
+            .health_check_timeout_milliseconds
+            .unwrap_or(DEFAULT_HEALTH_CHECK_TIMEOUT_MILLISECONDS),
+    );
+    let migrations = Arc::new(health::MigrationStateCache::new(db_pool.clone()));
+    let startup_probe = Arc::new(health::StartupProbe::new(
+        health::HealthChecks::new(health_check_timeout)
+            .register(health::DatabaseCheck(db_pool.clone()))
+            .register(health::MigrationStateCheck(migrations.clone()))
+            .register(health::NewerMigrationsCheck(migrations.clone())),
     ));
+    let mut health_checks = health::HealthChecks::new(health_check_timeout)
+        .register(health::ShutdownCheck(draining.clone()))
+        .register(health::DatabaseCheck(db_pool.clone()))
+        .register(health::PoolCapacityCheck {
+            pool: db_pool.clone(),
+            max_size: db::pool_max_size(&config.database),
+        })
+        .register(health::MigrationStateCheck(migrations.clone()))
+        .register(health::NewerMigrationsCheck(migrations));
+    // The check is not critical, so a bad endpoint only disables it.
+    match health::TracingExporterCheck::from_env() {
+        Ok(check) => health_checks = health_checks.register(check),
+        Err(e) => warn!("Not checking the tracing exporter: {:#}", e),
+    }
+    let health_checks = Arc::new(health_checks);
+    let global_concurrency_semapshore = Arc::new(Semaphore::new(config_reload::max_concurrent_connections(&config)));
+    let request_timeout_milliseconds = Arc::new(AtomicU64::new(config.service.request_timeout_milliseconds));
+
+    let apikey_auth = apikey_auth::ApiKeyAuth::from_apikeys(config.apikeys.clone());
+    let auth_layer = ValidateRequestHeaderLayer::custom(apikey_auth.clone());
+
+    let shutdown = shutdown_signal::shared_shutdown_signal(
+        draining,
+        Duration::from_millis(
+            config
+                .service
+                .shutdown_pre_stop_delay_milliseconds
+                .unwrap_or(DEFAULT_SHUTDOWN_PRE_STOP_DELAY_MILLISECONDS),
+        ),
+    );
+    let drain_timeout = Duration::from_millis(
+        config
+            .service
+            .shutdown_drain_timeout_milliseconds
+            .unwrap_or(DEFAULT_SHUTDOWN_DRAIN_TIMEOUT_MILLISECONDS),
+    );
+    let metrics_bind_address = config.metrics.bind_address.clone();
+    let bind_address = config.service.bind_address.clone();
 
-    let auth_layer = ValidateRequestHeaderLayer::custom(apikey_auth::ApiKeyAuth::from_apikeys(config.apikeys));
+    tokio::spawn(
+        config_reload::ConfigReloader {
+            config_path,
+            config,
+            apikey_auth,
+            log_filter: Some(log_filter),
+            request_timeout_milliseconds: request_timeout_milliseconds.clone(),
+            global_concurrency_semaphore: global_concurrency_semapshore.clone(),
+        }
+        .run(shutdown.clone()),
+    );
 
     let monitoring = Router::new()
         .route("/liveness", get(http_methods::liveness))
-        .route("/readiness", get(http_methods::database_ping))
-        .route("/metrics", get(appmetrics::scrape));
+        .route("/startup", get(health::startup))
+        .route("/readiness", get(health::readiness));
+
+    // When a dedicated metrics listener is configured, metrics are served only there and not on the public port.
+    let (monitoring, metrics_server) = match &metrics_bind_address {
+        Some(metrics_bind_address) => {
+            let metrics_app = monitoring
+                .clone()
+                .route("/info", get(info::info))
+                .route("/metrics", get(appmetrics::scrape))
+                .layer(Extension(db_pool.clone()))
+                .layer(Extension(health_checks.clone()))
+                .layer(Extension(startup_probe.clone()))
+                .layer(Extension(service_info.clone()))
+                .layer(Extension(prometheus_handle.clone()))
+                .layer(Extension(exemplars.clone()))
+                .layer(Extension(global_concurrency_semapshore.clone()));
+
+            info!("Binding metrics to {}", metrics_bind_address);
+            let listener = tokio::net::TcpListener::bind(metrics_bind_address).await?;
+            let metrics_server = axum::serve(listener, metrics_app)
+                .with_graceful_shutdown(shutdown_signal::wait_for_shutdown(shutdown.clone()));
+
+            (monitoring, Some(metrics_server))
+        }
+        None => (
+            monitoring
+                .route("/info", get(info::info).route_layer(auth_layer.clone()))
+                .route("/metrics", get(appmetrics::scrape)),
+            None,
+        ),
+    };
 
     let app = Router::new()
         .route("/", get(http_methods::default))
@@ -113,6 +246,7 @@ async fn service(config: Config) -> anyhow::Result<()> {
         .route("/cbor-ping/{id}", post(http_methods::cbor_ping))
         .layer(middleware::from_fn(appmetrics::auth_snooper))
         .layer(auth_layer)
+        .layer(middleware::from_fn(appmetrics::trace_id_snooper))
         .layer(
             ServiceBuilder::new()
                 // `LoadShedLayer` may inject errors, therefore it must be preceded with `HandleErrorLayer`.
@@ -121,28 +255,58 @@ async fn service(config: Config) -> anyhow::Result<()> {
                 .layer(GlobalConcurrencyLimitLayer::with_semaphore(
                     global_concurrency_semapshore.clone(),
                 ))
-                .layer(TimeoutLayer::new(Duration::from_millis(
-                    config.service.request_timeout_milliseconds,
-                )))
+                .layer(config_reload::ReloadableTimeoutLayer::new(request_timeout_milliseconds))
                 .layer(TraceLayer::new(
                     StatusInRangeAsFailures::new(400..=599).into_make_classifier(),
                 )),
         )
         .nest("/monitoring", monitoring)
         .layer(Extension(db_pool))
+        .layer(Extension(health_checks))
+        .layer(Extension(startup_probe))
+        .layer(Extension(service_info))
         .layer(Extension(prometheus_handle))
         .layer(Extension(global_concurrency_semapshore))
         .layer(CompressionLayer::new())
         // metrics tracking middleware should come after the service so it can also track errors from all layers
-        .layer(middleware::from_fn(appmetrics::track_latency));
-
-    let bind_address = &config.service.bind_address;
+        .layer(middleware::from_fn(appmetrics::track_latency))
+        .layer(Extension(label_guards))
+        .layer(Extension(exemplars));
 
     info!("Binding service to {}", bind_address);
     let listener = tokio::net::TcpListener::bind(bind_address).await.unwrap();
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal::shutdown_signal())
-        .await?;
+    let server =
+        axum::serve(listener, app).with_graceful_shutdown(shutdown_signal::wait_for_shutdown(shutdown.clone()));
+
+    let servers = async {
+        match metrics_server {
+            Some(metrics_server) => tokio::try_join!(server.into_future(), metrics_server.into_future()).map(|_| ()),
+            None => server.await,
+        }
+    };
+
+    let drain_deadline = async {
+        shutdown_signal::wait_for_shutdown(shutdown).await;
+        info!(
+            "Draining {} in-flight requests (deadline {:?})",
+            appmetrics::in_flight_requests(),
+            drain_timeout
+        );
+        tokio::time::sleep(drain_timeout).await;
+    };
+
+    tokio::select! {
+        result = servers => {
+            result?;
+            info!("All in-flight requests drained");
+        }
+        _ = drain_deadline => {
+            warn!(
+                "Drain deadline exceeded, cutting off {} in-flight requests",
+                appmetrics::in_flight_requests()
+            );
+        }
+    }
 
     Ok(())
 }
@@ -153,19 +317,68 @@ fn generate_openapi() -> anyhow::Result<()> {
 }
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let config = Config::read_default()?;
+async fn main() -> ExitCode {
+    let cli = match cli::parse() {
+        Ok(cli) => cli,
+        Err(exit_code) => return exit_code,
+    };
+    let config_path = Config::path(cli.config);
+
+    match run(cli.command.unwrap_or(Command::Serve), config_path).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => cli::exit_code(&e),
+    }
+}
 
-    if let Some(command) = std::env::args().nth(1) {
-        return run_command(&command, config).await;
+async fn run(command: Command, config_path: String) -> anyhow::Result<()> {
+    // The service sets up tracing itself, once the config is read.
+    if !matches!(command, Command::Serve) {
+        crate::apptracing::setup_basic_logging()?;
     }
 
-    // Invoked without a command: run the service
-    let tracer_provider = crate::apptracing::setup_tracing(SERVICE_NAME)?;
+    match command {
+        Command::Serve => serve(config_path).await,
+        Command::Openapi => generate_openapi(),
+        Command::ConfigSchema => generate_config_schema(),
+        Command::ConfigCheck => config_check(&config_path),
+        Command::NewMigration { name, down, dir } => database_migrations::new_migration(&dir, &name, down),
+        Command::VerifyMigrationVersioning { strict } => database_migrations::verify_migration_versioning(strict),
+        Command::Migrate { target, dry_run } => {
+            let config = Config::read(&config_path)?;
+            run_with_metrics_push(
+                &config.metrics,
+                database_migrations::refinery_migrate(&config, dry_run, target),
+            )
+            .await
+        }
+        Command::Rollback { to } => {
+            let config = Config::read(&config_path)?;
+            run_with_metrics_push(&config.metrics, database_migrations::rollback(&config, to)).await
+        }
+        Command::SchemaDump => schema_snapshot::schema_dump(&Config::read(&config_path)?).await,
+        Command::SchemaDiff {
+            dump,
+            other_dump,
+            scratch,
+        } => {
+            schema_snapshot::schema_diff(&config_path, dump.as_deref(), other_dump.as_deref(), scratch.as_deref()).await
+        }
+        Command::CheckMigrations { json } => {
+            let config = Config::read(&config_path)?;
+            run_with_metrics_push(&config.metrics, database_migrations::check_migrations(&config, json)).await
+        }
+    }
+}
+
+async fn serve(config_path: String) -> anyhow::Result<()> {
+    let config = Config::read(&config_path)?;
+
+    let (tracer_provider, log_filter) =
+        crate::apptracing::setup_tracing(SERVICE_NAME, config.service.log_filter.as_deref())?;
 
     debug!("config = {:#?}", config);
 
-    let result = service(config).await;
+    let result = service(config, config_path, log_filter).await;
 
     match &result {
         Ok(_) => info!("Normal service shutdown"),
@@ -178,16 +391,29 @@ async fn main() -> anyhow::Result<()> {
     result
 }
 
-async fn run_command(command: &str, config: Config) -> anyhow::Result<()> {
-    crate::apptracing::setup_basic_logging()?;
+fn generate_config_schema() -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(&schemars::schema_for!(Config))?);
+    Ok(())
+}
 
-    match command {
-        "openapi" => generate_openapi(),
-        "migrate" => database_migrations::refinery_migrate(&config.database.postgres_connection_string, false).await,
-        "check-migrations" => {
-            database_migrations::refinery_migrate(&config.database.postgres_connection_string, true).await
-        }
-        "verify-migration-versioning" => database_migrations::verify_migration_versioning(),
-        _ => Err(anyhow!("unknown command {}", command)),
-    }
+/// Prints the effective config with secrets redacted, or every problem found in it.
+fn config_check(config_path: &str) -> anyhow::Result<()> {
+    let config = Config::read(config_path)?;
+    println!("{}", serde_json::to_string_pretty(&config.redacted()?)?);
+    Ok(())
+}
+
+/// Runs a command, pushing its metrics when `[metrics.push]` is configured.
+async fn run_with_metrics_push(
+    metrics_config: &MetricsConfig,
+    command: impl Future<Output = anyhow::Result<()>>,
+) -> anyhow::Result<()> {
+    let Some(push_config) = &metrics_config.push else {
+        return command.await;
+    };
+
+    let prometheus_handle = Arc::new(appmetrics::install_prometheus(metrics_config)?);
+    metrics_push::MetricsPusher::new(push_config, prometheus_handle)
+        .run(command)
+        .await
 }
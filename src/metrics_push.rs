@@ -0,0 +1,224 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2022 Eldad Zack
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ */
+
+//! Push mode for short-lived commands, which exit before they can be scraped.
+//!
+//! The rendered metrics are sent to a Pushgateway-compatible endpoint periodically while the command runs and
+//! once more when it exits.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use metrics_exporter_prometheus::PrometheusHandle;
+use tracing::{debug, error};
+
+use crate::{config::PushConfig, openmetrics::CONTENT_TYPE_PROMETHEUS};
+
+pub const DEFAULT_PUSH_INTERVAL_SECS: u64 = 10;
+
+pub struct MetricsPusher {
+    client: reqwest::Client,
+    url: String,
+    interval: Duration,
+    prometheus_handle: Arc<PrometheusHandle>,
+}
+
+impl MetricsPusher {
+    pub fn new(config: &PushConfig, prometheus_handle: Arc<PrometheusHandle>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: config.url.clone(),
+            interval: Duration::from_secs(config.interval_secs.unwrap_or(DEFAULT_PUSH_INTERVAL_SECS)),
+            prometheus_handle,
+        }
+    }
+
+    pub async fn push(&self) -> anyhow::Result<()> {
+        let response = self
+            .client
+            // PUT replaces all metrics of the grouping key (e.g. `/metrics/job/shva`) in the Pushgateway.
+            .put(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, CONTENT_TYPE_PROMETHEUS)
+            .body(self.prometheus_handle.render())
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(anyhow!("pushing metrics to `{}` failed: {}", self.url, status));
+        }
+
+        debug!("pushed metrics to {}", self.url);
+        Ok(())
+    }
+
+    /// Runs `command`, pushing metrics periodically while it runs and once after it has finished.
+    /// Push failures are logged and do not affect the result of the command.
+    pub async fn run<F>(self, command: F) -> anyhow::Result<()>
+    where
+        F: Future<Output = anyhow::Result<()>>,
+    {
+        let periodic_push = async {
+            let mut ticker = tokio::time::interval(self.interval);
+            // The first tick completes immediately; there is nothing worth pushing yet.
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if let Err(err) = self.push().await {
+                    error!(error = %err, "periodic metrics push failed");
+                }
+            }
+        };
+
+        let result = tokio::select! {
+            result = command => result,
+            _ = periodic_push => unreachable!("periodic push never completes"),
+        };
+
+        if let Err(err) = self.push().await {
+            error!(error = %err, "final metrics push failed");
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    use super::*;
+
+    struct PushedRequest {
+        method: String,
+        path: String,
+        content_type: Option<String>,
+        body: String,
+    }
+
+    /// Accepts a single HTTP request and answers it with `status`.
+    async fn stub_pushgateway(status: &'static str) -> (String, JoinHandle<PushedRequest>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).await.unwrap();
+            let mut request_line = request_line.split_whitespace();
+            let method = request_line.next().unwrap().to_owned();
+            let path = request_line.next().unwrap().to_owned();
+
+            let mut content_length = 0;
+            let mut content_type = None;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).await.unwrap();
+                let header = header.trim_end();
+                if header.is_empty() {
+                    break;
+                }
+                let (name, value) = header.split_once(':').unwrap();
+                match name.to_ascii_lowercase().as_str() {
+                    "content-length" => content_length = value.trim().parse().unwrap(),
+                    "content-type" => content_type = Some(value.trim().to_owned()),
+                    _ => {}
+                }
+            }
+
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).await.unwrap();
+            reader
+                .into_inner()
+                .write_all(format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+
+            PushedRequest {
+                method,
+                path,
+                content_type,
+                body: String::from_utf8(body).unwrap(),
+            }
+        });
+
+        (format!("http://{address}/metrics/job/shva"), server)
+    }
+
+    fn pusher(url: String) -> MetricsPusher {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let prometheus_handle = Arc::new(recorder.handle());
+        metrics::with_local_recorder(&recorder, || {
+            metrics::counter!("migrations_applied_total").increment(2);
+        });
+
+        MetricsPusher::new(
+            &PushConfig {
+                url,
+                interval_secs: None,
+            },
+            prometheus_handle,
+        )
+    }
+
+    #[tokio::test]
+    async fn pushes_the_rendered_metrics() {
+        let (url, server) = stub_pushgateway("200 OK").await;
+
+        pusher(url).push().await.unwrap();
+
+        let request = server.await.unwrap();
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/metrics/job/shva");
+        assert_eq!(request.content_type.as_deref(), Some(CONTENT_TYPE_PROMETHEUS));
+        assert!(request.body.contains("migrations_applied_total 2"), "{}", request.body);
+    }
+
+    #[tokio::test]
+    async fn run_pushes_when_the_command_finishes() {
+        let (url, server) = stub_pushgateway("200 OK").await;
+
+        pusher(url).run(async { Ok(()) }).await.unwrap();
+
+        assert!(server.await.unwrap().body.contains("migrations_applied_total 2"));
+    }
+
+    #[tokio::test]
+    async fn rejected_pushes_are_errors() {
+        let (url, server) = stub_pushgateway("400 Bad Request").await;
+
+        let error = pusher(url).push().await.unwrap_err();
+
+        assert!(error.to_string().contains("400"), "{error}");
+        server.await.unwrap();
+    }
+}
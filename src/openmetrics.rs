@@ -0,0 +1,325 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2022 Eldad Zack
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ */
+
+//! OpenMetrics rendering with exemplars.
+//!
+//! `metrics-exporter-prometheus` renders only the Prometheus text format and has no notion of exemplars.
+//! Exemplars are therefore tracked here, per histogram series and bucket, and merged into the rendered text
+//! when the scraper asks for OpenMetrics.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::{HeaderMap, header};
+
+pub const CONTENT_TYPE_OPENMETRICS: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+pub const CONTENT_TYPE_PROMETHEUS: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// The OpenTelemetry trace id of a request, passed from inside the trace span to the metrics middleware
+/// via the response extensions.
+#[derive(Debug, Clone)]
+pub struct TraceId(pub String);
+
+#[derive(Clone)]
+struct Exemplar {
+    trace_id: String,
+    value: f64,
+    timestamp: f64,
+}
+
+/// Latest exemplar per bucket for every series of a single histogram.
+pub struct Exemplars {
+    metric_name: String,
+    label_names: Vec<&'static str>,
+    buckets: Vec<f64>,
+    series: Mutex<HashMap<Vec<String>, Vec<Option<Exemplar>>>>,
+}
+
+impl Exemplars {
+    /// `metric_name` is the rendered (prefixed) name, `buckets` the bucket upper bounds of the histogram.
+    pub fn new(metric_name: String, label_names: Vec<&'static str>, buckets: Vec<f64>) -> Self {
+        Self {
+            metric_name,
+            label_names,
+            buckets,
+            series: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records an exemplar for an observation. `labels` must be in the order of `label_names`.
+    pub fn record(&self, labels: &[(&str, String)], value: f64, trace_id: String) {
+        let key = labels.iter().map(|(_, value)| escape_label_value(value)).collect();
+        // The last slot is the `+Inf` bucket.
+        let bucket = self
+            .buckets
+            .iter()
+            .position(|&upper_bound| value <= upper_bound)
+            .unwrap_or(self.buckets.len());
+        let timestamp = unix_time();
+
+        let mut series = self.series.lock().expect("exemplars lock poisoned");
+        let exemplars = series.entry(key).or_insert_with(|| vec![None; self.buckets.len() + 1]);
+        exemplars[bucket] = Some(Exemplar {
+            trace_id,
+            value,
+            timestamp,
+        });
+    }
+
+    /// Forgets the series without an exemplar recorded within `idle_timeout`, as the exporter forgets idle series.
+    pub fn evict_idle(&self, idle_timeout: Duration) {
+        let oldest = unix_time() - idle_timeout.as_secs_f64();
+        let mut series = self.series.lock().expect("exemplars lock poisoned");
+        series.retain(|_, exemplars| exemplars.iter().flatten().any(|exemplar| exemplar.timestamp > oldest));
+    }
+
+    fn lookup(&self, labels: &HashMap<&str, &str>, le: &str) -> Option<Exemplar> {
+        let key: Option<Vec<String>> = self
+            .label_names
+            .iter()
+            .map(|name| labels.get(name).map(|value| value.to_string()))
+            .collect();
+        let bucket = match le {
+            "+Inf" => self.buckets.len(),
+            le => {
+                let upper_bound: f64 = le.parse().ok()?;
+                self.buckets.iter().position(|&bound| bound == upper_bound)?
+            }
+        };
+
+        let series = self.series.lock().expect("exemplars lock poisoned");
+        series.get(&key?)?.get(bucket)?.clone()
+    }
+}
+
+fn unix_time() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs_f64())
+        .unwrap_or_default()
+}
+
+/// Whether the scraper accepts OpenMetrics (e.g. Prometheus with exemplar storage enabled).
+pub fn accepts_openmetrics(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains("application/openmetrics-text"))
+}
+
+/// Converts the Prometheus text format into OpenMetrics and attaches exemplars to histogram buckets.
+pub fn render(prometheus_text: &str, exemplars: &Exemplars) -> String {
+    let bucket_prefix = format!("{}_bucket{{", exemplars.metric_name);
+
+    // Counter families are named without the `_total` suffix, which is required on their samples.
+    let counters: HashMap<&str, bool> = prometheus_text
+        .lines()
+        .filter_map(|line| line.strip_prefix("# TYPE ")?.strip_suffix(" counter"))
+        .map(|name| (name, name.ends_with("_total")))
+        .collect();
+
+    let mut output = String::with_capacity(prometheus_text.len());
+
+    for line in prometheus_text.lines() {
+        // OpenMetrics does not allow empty lines.
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(metadata) = line.strip_prefix("# ") {
+            match metadata.split_once(' ') {
+                Some((kind, rest)) => {
+                    let (name, description) = rest.split_once(' ').unwrap_or((rest, ""));
+                    let name = match counters.contains_key(name) {
+                        true => name.strip_suffix("_total").unwrap_or(name),
+                        false => name,
+                    };
+                    match description.is_empty() {
+                        true => writeln!(output, "# {kind} {name}"),
+                        false => writeln!(output, "# {kind} {name} {description}"),
+                    }
+                    .expect("writing to a String never fails");
+                }
+                None => {
+                    output.push_str(line);
+                    output.push('\n');
+                }
+            }
+            continue;
+        }
+
+        let name_end = line.find(['{', ' ']).unwrap_or(line.len());
+        let (name, rest) = line.split_at(name_end);
+        if counters.get(name) == Some(&false) {
+            writeln!(output, "{name}_total{rest}").expect("writing to a String never fails");
+            continue;
+        }
+
+        output.push_str(line);
+        if line.starts_with(&bucket_prefix)
+            && let Some(exemplar) = bucket_exemplar(line, exemplars)
+        {
+            write!(
+                output,
+                " # {{trace_id=\"{}\"}} {} {:.3}",
+                exemplar.trace_id, exemplar.value, exemplar.timestamp
+            )
+            .expect("writing to a String never fails");
+        }
+        output.push('\n');
+    }
+
+    output.push_str("# EOF\n");
+    output
+}
+
+fn bucket_exemplar(line: &str, exemplars: &Exemplars) -> Option<Exemplar> {
+    let labels_start = line.find('{')? + 1;
+    let labels_end = line.rfind('}')?;
+    let labels = parse_labels(&line[labels_start..labels_end]);
+    let le = labels.get("le")?;
+    exemplars.lookup(&labels, le)
+}
+
+/// Parses a rendered label set (`a="x",b="y"`), keeping the values escaped.
+fn parse_labels(labels: &str) -> HashMap<&str, &str> {
+    let mut parsed = HashMap::new();
+    let mut rest = labels;
+
+    while let Some((name, after_name)) = rest.split_once("=\"") {
+        let mut escaped = false;
+        let Some(value_end) = after_name.char_indices().find_map(|(index, c)| {
+            let end = !escaped && c == '"';
+            escaped = !escaped && c == '\\';
+            end.then_some(index)
+        }) else {
+            break;
+        };
+        parsed.insert(name.trim_start_matches(','), &after_name[..value_end]);
+        rest = &after_name[value_end + 1..];
+    }
+
+    parsed
+}
+
+/// Escapes a label value the same way the Prometheus exporter does.
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn exemplars() -> Exemplars {
+        Exemplars::new("latency".to_owned(), vec!["path"], vec![0.1, 1.0])
+    }
+
+    #[test]
+    fn counter_samples_get_the_total_suffix() {
+        let prometheus_text = "# TYPE requests counter\nrequests{path=\"/\"} 3\n\n";
+        assert_eq!(
+            render(prometheus_text, &exemplars()),
+            "# TYPE requests counter\nrequests_total{path=\"/\"} 3\n# EOF\n"
+        );
+    }
+
+    #[test]
+    fn counter_families_lose_the_total_suffix() {
+        let prometheus_text = "# HELP dropped_total Dropped values\n# TYPE dropped_total counter\ndropped_total 1\n";
+        assert_eq!(
+            render(prometheus_text, &exemplars()),
+            "# HELP dropped Dropped values\n# TYPE dropped counter\ndropped_total 1\n# EOF\n"
+        );
+    }
+
+    #[test]
+    fn idle_exemplars_are_evicted() {
+        let exemplars = exemplars();
+        exemplars.record(&[("path", "/".to_owned())], 0.5, "trace".to_owned());
+        let prometheus_text = "latency_bucket{path=\"/\",le=\"1\"} 1\n";
+
+        exemplars.evict_idle(Duration::from_secs(60));
+        assert!(render(prometheus_text, &exemplars).contains("trace_id=\"trace\""));
+
+        exemplars.evict_idle(Duration::ZERO);
+        assert!(!render(prometheus_text, &exemplars).contains("trace_id"));
+    }
+
+    #[test]
+    fn exemplars_are_attached_to_their_bucket() {
+        let exemplars = exemplars();
+        exemplars.record(
+            &[("path", "/a\"b".to_owned())],
+            0.5,
+            "0af7651916cd43dd8448eb211c80319c".to_owned(),
+        );
+
+        let prometheus_text = "# TYPE latency histogram\n\
+            latency_bucket{path=\"/a\\\"b\",le=\"0.1\"} 0\n\
+            latency_bucket{path=\"/a\\\"b\",le=\"1\"} 1\n\
+            latency_bucket{path=\"/a\\\"b\",le=\"+Inf\"} 1\n\
+            latency_bucket{path=\"/other\",le=\"1\"} 1\n";
+        let rendered = render(prometheus_text, &exemplars);
+        let lines: Vec<&str> = rendered.lines().collect();
+
+        assert_eq!(lines[1], "latency_bucket{path=\"/a\\\"b\",le=\"0.1\"} 0");
+        assert!(
+            lines[2].starts_with(
+                "latency_bucket{path=\"/a\\\"b\",le=\"1\"} 1 # {trace_id=\"0af7651916cd43dd8448eb211c80319c\"} 0.5 "
+            ),
+            "{}",
+            lines[2]
+        );
+        assert_eq!(lines[3], "latency_bucket{path=\"/a\\\"b\",le=\"+Inf\"} 1");
+        assert_eq!(lines[4], "latency_bucket{path=\"/other\",le=\"1\"} 1");
+        assert_eq!(lines[5], "# EOF");
+    }
+
+    #[test]
+    fn labels_are_parsed_with_escaped_quotes() {
+        let labels = parse_labels(r#"path="/a\"b",le="0.5""#);
+        assert_eq!(labels.get("path"), Some(&r#"/a\"b"#));
+        assert_eq!(labels.get("le"), Some(&"0.5"));
+    }
+
+    #[test]
+    fn openmetrics_is_negotiated_from_the_accept_header() {
+        let mut headers = HeaderMap::new();
+        assert!(!accepts_openmetrics(&headers));
+        headers.insert(
+            header::ACCEPT,
+            HeaderValue::from_static("application/openmetrics-text;version=1.0.0,text/plain;q=0.5"),
+        );
+        assert!(accepts_openmetrics(&headers));
+    }
+}
@@ -0,0 +1,301 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2022 Eldad Zack
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ */
+
+use std::{fs, str::FromStr, sync::Arc};
+
+use anyhow::{Context, anyhow};
+use rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    client::{
+        WebPkiServerVerifier,
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    },
+    crypto::{CryptoProvider, WebPkiSupportedAlgorithms},
+};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject};
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tracing::warn;
+
+use crate::config::{DatabaseConfig, SslMode};
+
+/// Parses the connection string, with `sslmode` overridden by `database.sslmode` when set.
+pub fn postgres_config(database_config: &DatabaseConfig) -> anyhow::Result<tokio_postgres::Config> {
+    let mut postgres_config = tokio_postgres::Config::from_str(&database_config.postgres_connection_string)?;
+    if let Some(sslmode) = database_config.sslmode {
+        postgres_config.ssl_mode(match sslmode {
+            SslMode::Disable => tokio_postgres::config::SslMode::Disable,
+            SslMode::Prefer => tokio_postgres::config::SslMode::Prefer,
+            SslMode::Require | SslMode::VerifyCa | SslMode::VerifyFull => tokio_postgres::config::SslMode::Require,
+        });
+    }
+    Ok(postgres_config)
+}
+
+/// The rustls connector for `database_config`. Like libpq, `prefer` and `require` (and the default, `prefer`) encrypt
+/// without checking the server certificate, `verify-ca` checks that a trusted CA signed it, and `verify-full` also
+/// checks that it was issued for the host.
+pub fn tls_connect(database_config: &DatabaseConfig) -> anyhow::Result<MakeRustlsConnect> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(server_verifier(database_config, provider)?);
+
+    let config = match (&database_config.sslcert, &database_config.sslkey) {
+        (Some(cert), Some(key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err(anyhow!("database.sslcert and database.sslkey must be set together")),
+    };
+
+    Ok(MakeRustlsConnect::new(config))
+}
+
+/// Checks the server certificate as `database.sslmode` asks for.
+fn server_verifier(
+    database_config: &DatabaseConfig,
+    provider: Arc<CryptoProvider>,
+) -> anyhow::Result<Arc<dyn ServerCertVerifier>> {
+    Ok(match database_config.sslmode.unwrap_or(SslMode::Prefer) {
+        SslMode::Disable | SslMode::Prefer | SslMode::Require => {
+            Arc::new(NoServerVerification(provider.signature_verification_algorithms))
+        }
+        SslMode::VerifyCa => Arc::new(NoHostVerification(
+            WebPkiServerVerifier::builder_with_provider(Arc::new(root_certs(database_config)?), provider).build()?,
+        )),
+        SslMode::VerifyFull => {
+            WebPkiServerVerifier::builder_with_provider(Arc::new(root_certs(database_config)?), provider).build()?
+        }
+    })
+}
+
+/// CAs from `database.sslrootcert`, or the system's when unset.
+fn root_certs(database_config: &DatabaseConfig) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match &database_config.sslrootcert {
+        Some(path) => {
+            for cert in load_certs(path)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("invalid CA certificate in {path}"))?;
+            }
+        }
+        None => {
+            let native = rustls_native_certs::load_native_certs();
+            for e in &native.errors {
+                warn!("Failed to load system CA certificates: {}", e);
+            }
+            roots.add_parsable_certificates(native.certs);
+        }
+    }
+    if roots.is_empty() {
+        return Err(anyhow!("no CA certificates to verify the database server with"));
+    }
+    Ok(roots)
+}
+
+/// Reads the certificates of a PEM file.
+pub fn load_certs(path: &str) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let pem = fs::read(path).with_context(|| format!("failed to read {path}"))?;
+    let certs = CertificateDer::pem_slice_iter(&pem)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid PEM in {path}"))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificates in {path}"));
+    }
+    Ok(certs)
+}
+
+/// Reads the private key of a PEM file (PKCS#8, PKCS#1 or SEC1).
+pub fn load_key(path: &str) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let pem = fs::read(path).with_context(|| format!("failed to read {path}"))?;
+    PrivateKeyDer::from_pem_slice(&pem).with_context(|| format!("no private key in {path}"))
+}
+
+/// Accepts any server certificate, as `sslmode=require` does: the connection is encrypted, but not authenticated.
+#[derive(Debug)]
+struct NoServerVerification(WebPkiSupportedAlgorithms);
+
+impl ServerCertVerifier for NoServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
+
+/// Verifies the certificate chain but not the host name, as `sslmode=verify-ca` does.
+#[derive(Debug)]
+struct NoHostVerification(Arc<WebPkiServerVerifier>);
+
+impl ServerCertVerifier for NoHostVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match self
+            .0
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+        {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::NotValidForName | rustls::CertificateError::NotValidForNameContext { .. },
+            )) => Ok(ServerCertVerified::assertion()),
+            result => result,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/postgres_tls");
+
+    fn fixture(name: &str) -> String {
+        format!("{FIXTURES}/{name}")
+    }
+
+    /// Verifies the `db.example` fixture certificates, signed by `ca.crt` or `untrusted.crt` (self-signed), as `host`.
+    fn verify(sslmode: SslMode, cert: &str, host: &str) -> Result<ServerCertVerified, rustls::Error> {
+        let database_config = DatabaseConfig {
+            sslmode: Some(sslmode),
+            sslrootcert: Some(fixture("ca.crt")),
+            ..Default::default()
+        };
+        let verifier = server_verifier(&database_config, Arc::new(rustls::crypto::ring::default_provider())).unwrap();
+        let cert = load_certs(&fixture(cert)).unwrap().remove(0);
+        verifier.verify_server_cert(&cert, &[], &ServerName::try_from(host).unwrap(), &[], UnixTime::now())
+    }
+
+    #[test]
+    fn unverified_modes_accept_any_certificate() {
+        for sslmode in [SslMode::Disable, SslMode::Prefer, SslMode::Require] {
+            assert!(verify(sslmode, "untrusted.crt", "other.example").is_ok(), "{sslmode:?}");
+        }
+    }
+
+    #[test]
+    fn verify_ca_checks_the_issuer_but_not_the_host() {
+        assert!(verify(SslMode::VerifyCa, "server.crt", "db.example").is_ok());
+        assert!(verify(SslMode::VerifyCa, "server.crt", "other.example").is_ok());
+        assert!(verify(SslMode::VerifyCa, "untrusted.crt", "db.example").is_err());
+    }
+
+    #[test]
+    fn verify_full_checks_the_issuer_and_the_host() {
+        assert!(verify(SslMode::VerifyFull, "server.crt", "db.example").is_ok());
+        assert!(verify(SslMode::VerifyFull, "server.crt", "other.example").is_err());
+        assert!(verify(SslMode::VerifyFull, "untrusted.crt", "db.example").is_err());
+    }
+
+    #[test]
+    fn loads_certificates_and_keys() {
+        assert_eq!(load_certs(&fixture("ca.crt")).unwrap().len(), 1);
+        assert!(load_key(&fixture("server.key")).is_ok());
+    }
+
+    fn error<T>(result: anyhow::Result<T>) -> String {
+        format!("{:#}", result.err().expect("an error"))
+    }
+
+    #[test]
+    fn rejects_missing_and_malformed_pem() {
+        let directory = std::env::temp_dir().join(format!("shva-postgres-tls-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let file = |name: &str, content: &str| {
+            let path = directory.join(name);
+            fs::write(&path, content).unwrap();
+            path.to_str().unwrap().to_owned()
+        };
+        let empty = file("empty.pem", "");
+        let truncated = file("truncated.pem", "-----BEGIN CERTIFICATE-----\nMIIB\n");
+        let garbage = file(
+            "garbage.pem",
+            "-----BEGIN CERTIFICATE-----\n!!!!\n-----END CERTIFICATE-----\n",
+        );
+
+        assert!(error(load_certs(&fixture("missing.crt"))).starts_with("failed to read"));
+        assert!(error(load_certs(&empty)).starts_with("no certificates in"));
+        assert!(error(load_certs(&truncated)).starts_with("invalid PEM in"));
+        assert!(error(load_certs(&garbage)).starts_with("invalid PEM in"));
+        assert!(error(load_key(&fixture("missing.key"))).starts_with("failed to read"));
+        assert!(error(load_key(&empty)).starts_with("no private key in"));
+        // A certificate is no key.
+        assert!(error(load_key(&fixture("ca.crt"))).starts_with("no private key in"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2022 Eldad Zack
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ */
+
+//! Process and Tokio runtime gauges, refreshed on every scrape.
+
+use tokio::runtime::Handle;
+
+pub fn update_tokio_metric_gauges() {
+    let runtime_metrics = Handle::current().metrics();
+    let num_workers = runtime_metrics.num_workers();
+
+    metrics::gauge!("tokio_workers").set(num_workers as f64);
+    metrics::gauge!("tokio_alive_tasks").set(runtime_metrics.num_alive_tasks() as f64);
+    metrics::gauge!("tokio_global_queue_depth").set(runtime_metrics.global_queue_depth() as f64);
+
+    #[cfg(target_has_atomic = "64")]
+    {
+        let busy_duration: f64 = (0..num_workers)
+            .map(|worker| runtime_metrics.worker_total_busy_duration(worker).as_secs_f64())
+            .sum();
+        let park_count: u64 = (0..num_workers)
+            .map(|worker| runtime_metrics.worker_park_count(worker))
+            .sum();
+
+        metrics::gauge!("tokio_workers_busy_seconds").set(busy_duration);
+        metrics::counter!("tokio_workers_park_total").absolute(park_count);
+    }
+
+    // These are only available when building with `RUSTFLAGS="--cfg tokio_unstable"`, see the README.
+    #[cfg(tokio_unstable)]
+    {
+        let poll_count: u64 = (0..num_workers)
+            .map(|worker| runtime_metrics.worker_poll_count(worker))
+            .sum();
+
+        metrics::gauge!("tokio_blocking_threads").set(runtime_metrics.num_blocking_threads() as f64);
+        metrics::gauge!("tokio_idle_blocking_threads").set(runtime_metrics.num_idle_blocking_threads() as f64);
+        metrics::counter!("tokio_workers_polls_total").absolute(poll_count);
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn update_process_metric_gauges() {
+    if let Err(err) = linux::update_process_metric_gauges() {
+        tracing::warn!(error = %err, "failed to read process metrics from /proc");
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn update_process_metric_gauges() {}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs;
+
+    use anyhow::anyhow;
+
+    /// `/proc` reports times in USER_HZ, which is fixed to 100 on Linux regardless of the kernel `HZ`.
+    const USER_HZ: f64 = 100.0;
+
+    pub(super) fn update_process_metric_gauges() -> anyhow::Result<()> {
+        let stat = fs::read_to_string("/proc/self/stat")?;
+
+        // The command name (2nd field) is enclosed in parentheses and may contain spaces, so split after it.
+        // Indexing below is relative to the 3rd field (`state`), see proc(5).
+        let fields: Vec<&str> = stat
+            .rsplit_once(')')
+            .ok_or_else(|| anyhow!("malformed /proc/self/stat"))?
+            .1
+            .split_whitespace()
+            .collect();
+        let field = |index: usize| -> anyhow::Result<u64> {
+            Ok(fields
+                .get(index - 3)
+                .ok_or_else(|| anyhow!("missing field {} in /proc/self/stat", index))?
+                .parse()?)
+        };
+
+        let cpu_ticks = field(14)? + field(15)?;
+        let threads = field(20)?;
+        let start_ticks = field(22)?;
+
+        // Not counting the fd `read_dir` itself holds open on `/proc/self/fd`.
+        let open_fds = fs::read_dir("/proc/self/fd")?.count().saturating_sub(1);
+
+        // A counter, as dashboards expect, at the cost of sub-second precision.
+        metrics::counter!("process_cpu_seconds_total").absolute(cpu_ticks / USER_HZ as u64);
+        metrics::gauge!("process_threads").set(threads as f64);
+        metrics::gauge!("process_resident_memory_bytes").set(resident_memory_bytes()? as f64);
+        metrics::gauge!("process_open_fds").set(open_fds as f64);
+        metrics::gauge!("process_start_time_seconds").set(boot_time()? as f64 + start_ticks as f64 / USER_HZ);
+
+        Ok(())
+    }
+
+    fn boot_time() -> anyhow::Result<u64> {
+        fs::read_to_string("/proc/stat")?
+            .lines()
+            .find_map(|line| line.strip_prefix("btime "))
+            .ok_or_else(|| anyhow!("btime missing from /proc/stat"))?
+            .trim()
+            .parse()
+            .map_err(Into::into)
+    }
+
+    fn resident_memory_bytes() -> anyhow::Result<u64> {
+        let kilobytes: u64 = fs::read_to_string("/proc/self/status")?
+            .lines()
+            .find_map(|line| line.strip_prefix("VmRSS:"))
+            .ok_or_else(|| anyhow!("VmRSS missing from /proc/self/status"))?
+            .trim()
+            .trim_end_matches("kB")
+            .trim()
+            .parse()?;
+        Ok(kilobytes * 1024)
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+    #[test]
+    fn process_metrics_are_read_from_proc() {
+        let recorder = DebuggingRecorder::new();
+        metrics::with_local_recorder(&recorder, super::linux::update_process_metric_gauges).unwrap();
+
+        let values: Vec<(String, f64)> = recorder
+            .snapshotter()
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .filter_map(|(key, .., value)| match value {
+                DebugValue::Gauge(value) => Some((key.key().name().to_owned(), value.into_inner())),
+                DebugValue::Counter(value) => Some((key.key().name().to_owned(), value as f64)),
+                _ => None,
+            })
+            .collect();
+        let value = |name: &str| {
+            values
+                .iter()
+                .find(|(metric, _)| metric == name)
+                .map(|&(_, value)| value)
+                .unwrap_or_else(|| panic!("{name} not recorded"))
+        };
+
+        assert!(value("process_threads") >= 1.0);
+        assert!(value("process_resident_memory_bytes") > 0.0);
+        // At least stdin, stdout and stderr.
+        assert!(value("process_open_fds") >= 3.0);
+        assert!(value("process_cpu_seconds_total") >= 0.0);
+        assert!(value("process_start_time_seconds") > 0.0);
+    }
+}
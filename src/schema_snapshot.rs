@@ -0,0 +1,295 @@
+/*
+ * MIT License
+ *
+ * Copyright (c) 2022 Eldad Zack
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in all
+ * copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ * AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ *
+ */
+
+use std::{collections::BTreeSet, fs, path::Path};
+
+use anyhow::anyhow;
+
+use crate::{
+    config::{Config, DatabaseConfig},
+    database_migrations::{self, MIGRATION_TABLE_NAME},
+    db,
+};
+
+/// Schemas created by PostgreSQL itself, excluded from snapshots.
+const SYSTEM_SCHEMAS_FILTER: &str = "n.nspname NOT IN ('pg_catalog', 'information_schema') AND n.nspname NOT LIKE 'pg\\_toast%' \
+     AND n.nspname NOT LIKE 'pg\\_temp\\_%'";
+
+/// Objects belonging to an extension are tracked by the extension line only.
+const NOT_FROM_EXTENSION_FILTER: &str =
+    "NOT EXISTS (SELECT 1 FROM pg_depend dep WHERE dep.objid = {oid} AND dep.deptype = 'e')";
+
+/// Lines of a dump starting with this are comments, ignored when comparing.
+const COMMENT_PREFIX: &str = "--";
+
+/// A normalized description of a database schema: one line per object, sorted, so that two snapshots can be
+/// compared line by line. The migration history table is left out.
+pub struct SchemaSnapshot {
+    lines: BTreeSet<String>,
+}
+
+impl SchemaSnapshot {
+    pub async fn from_database(client: &tokio_postgres::Client) -> anyhow::Result<Self> {
+        let mut lines = BTreeSet::new();
+
+        let not_from_extension = |oid: &str| NOT_FROM_EXTENSION_FILTER.replace("{oid}", oid);
+        let not_migration_table = format!("c.relname <> '{MIGRATION_TABLE_NAME}'");
+
+        let queries = [
+            "SELECT 'extension ' || extname FROM pg_extension WHERE extname <> 'plpgsql'".to_owned(),
+            format!("SELECT 'schema ' || n.nspname FROM pg_namespace n WHERE {SYSTEM_SCHEMAS_FILTER}"),
+            format!(
+                "SELECT 'type ' || n.nspname || '.' || t.typname || ' enum (' \
+                 || string_agg(quote_literal(e.enumlabel), ', ' ORDER BY e.enumsortorder) || ')' \
+                 FROM pg_type t JOIN pg_enum e ON e.enumtypid = t.oid JOIN pg_namespace n ON n.oid = t.typnamespace \
+                 WHERE {SYSTEM_SCHEMAS_FILTER} AND {} GROUP BY n.nspname, t.typname",
+                not_from_extension("t.oid")
+            ),
+            format!(
+                "SELECT CASE c.relkind WHEN 'r' THEN 'table ' WHEN 'p' THEN 'partitioned table ' \
+                 WHEN 'f' THEN 'foreign table ' WHEN 'S' THEN 'sequence ' WHEN 'v' THEN 'view ' \
+                 ELSE 'materialized view ' END || n.nspname || '.' || c.relname \
+                 || CASE WHEN c.relkind IN ('v', 'm') THEN ' as ' || pg_get_viewdef(c.oid) ELSE '' END \
+                 FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace \
+                 WHERE c.relkind IN ('r', 'p', 'f', 'S', 'v', 'm') AND {SYSTEM_SCHEMAS_FILTER} \
+                 AND {not_migration_table} AND {}",
+                not_from_extension("c.oid")
+            ),
+            // Positions are ranked rather than taken from `attnum`, which keeps the gaps left by dropped columns.
+            format!(
+                "SELECT 'column ' || n.nspname || '.' || c.relname || ' ' \
+                 || row_number() OVER (PARTITION BY c.oid ORDER BY a.attnum) || ' ' || a.attname || ' ' \
+                 || format_type(a.atttypid, a.atttypmod) \
+                 || CASE WHEN a.attnotnull THEN ' not null' ELSE '' END \
+                 || CASE a.attidentity WHEN 'a' THEN ' generated always as identity' \
+                 WHEN 'd' THEN ' generated by default as identity' ELSE '' END \
+                 || COALESCE(CASE WHEN a.attgenerated = 's' THEN ' generated always as ' ELSE ' default ' END \
+                 || pg_get_expr(d.adbin, d.adrelid), '') \
+                 FROM pg_attribute a JOIN pg_class c ON c.oid = a.attrelid \
+                 JOIN pg_namespace n ON n.oid = c.relnamespace \
+                 LEFT JOIN pg_attrdef d ON d.adrelid = a.attrelid AND d.adnum = a.attnum \
+                 WHERE a.attnum > 0 AND NOT a.attisdropped AND c.relkind IN ('r', 'p', 'f', 'v', 'm') \
+                 AND {SYSTEM_SCHEMAS_FILTER} AND {not_migration_table} AND {}",
+                not_from_extension("c.oid")
+            ),
+            format!(
+                "SELECT 'constraint ' || n.nspname || '.' || c.relname || ' ' || con.conname || ' ' \
+                 || pg_get_constraintdef(con.oid) \
+                 FROM pg_constraint con JOIN pg_class c ON c.oid = con.conrelid \
+                 JOIN pg_namespace n ON n.oid = c.relnamespace \
+                 WHERE {SYSTEM_SCHEMAS_FILTER} AND {not_migration_table} AND {}",
+                not_from_extension("c.oid")
+            ),
+            format!(
+                "SELECT 'index ' || n.nspname || '.' || c.relname || ' ' || pg_get_indexdef(i.indexrelid) \
+                 FROM pg_index i JOIN pg_class c ON c.oid = i.indrelid JOIN pg_namespace n ON n.oid = c.relnamespace \
+                 WHERE {SYSTEM_SCHEMAS_FILTER} AND {not_migration_table} AND {}",
+                not_from_extension("c.oid")
+            ),
+            format!(
+                "SELECT 'trigger ' || n.nspname || '.' || c.relname || ' ' || pg_get_triggerdef(t.oid) \
+                 FROM pg_trigger t JOIN pg_class c ON c.oid = t.tgrelid JOIN pg_namespace n ON n.oid = c.relnamespace \
+                 WHERE NOT t.tgisinternal AND {SYSTEM_SCHEMAS_FILTER} AND {}",
+                not_from_extension("c.oid")
+            ),
+            format!(
+                "SELECT 'function ' || pg_get_functiondef(p.oid) \
+                 FROM pg_proc p JOIN pg_namespace n ON n.oid = p.pronamespace \
+                 WHERE p.prokind IN ('f', 'p') AND {SYSTEM_SCHEMAS_FILTER} AND {}",
+                not_from_extension("p.oid")
+            ),
+        ];
+
+        for query in queries {
+            for row in client.query(&query, &[]).await? {
+                lines.insert(normalize(row.try_get(0)?));
+            }
+        }
+
+        Ok(Self { lines })
+    }
+
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let lines = fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read schema dump {}: {}", path.display(), e))?
+            .lines()
+            .filter(|line| !line.trim().is_empty() && !line.starts_with(COMMENT_PREFIX))
+            .map(normalize)
+            .collect();
+        Ok(Self { lines })
+    }
+
+    pub fn render(&self) -> String {
+        let mut dump = format!("{COMMENT_PREFIX} {} schema dump\n", env!("CARGO_PKG_NAME"));
+        for line in &self.lines {
+            dump.push_str(line);
+            dump.push('\n');
+        }
+        dump
+    }
+
+    /// Lines only in `self` (prefixed with `-`) and lines only in `other` (prefixed with `+`).
+    pub fn diff(&self, other: &SchemaSnapshot) -> Vec<String> {
+        let removed = self.lines.difference(&other.lines).map(|line| format!("- {line}"));
+        let added = other.lines.difference(&self.lines).map(|line| format!("+ {line}"));
+        removed.chain(added).collect()
+    }
+}
+
+/// Collapses whitespace, so that multi-line definitions fit on a single line.
+fn normalize(line: &str) -> String {
+    line.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Snapshot of the database of `database_config`.
+async fn snapshot_database(database_config: &DatabaseConfig) -> anyhow::Result<SchemaSnapshot> {
+    let pool = db::setup_pool(database_config).await?;
+    let conn = pool.get().await?;
+    SchemaSnapshot::from_database(&conn).await
+}
+
+/// Applies the embedded migrations to an empty scratch database, reached with the connection timeout and TLS settings
+/// of the configured `database`, and takes its snapshot: the schema the migrations produce.
+async fn snapshot_scratch_database(
+    database: &DatabaseConfig,
+    postgres_connection_string: &str,
+) -> anyhow::Result<SchemaSnapshot> {
+    let pool = db::setup_pool(&DatabaseConfig {
+        postgres_connection_string: postgres_connection_string.to_owned(),
+        connection_timeout_secs: database.connection_timeout_secs,
+        sslmode: database.sslmode,
+        sslrootcert: database.sslrootcert.clone(),
+        sslcert: database.sslcert.clone(),
+        sslkey: database.sslkey.clone(),
+        ..Default::default()
+    })
+    .await?;
+    let mut conn = pool.get().await?;
+
+    let existing = SchemaSnapshot::from_database(&conn).await?;
+    if existing.lines.iter().any(|line| !line.starts_with("schema ")) {
+        return Err(anyhow!("the scratch database must be empty"));
+    }
+
+    database_migrations::migrate_scratch(&mut conn).await?;
+    SchemaSnapshot::from_database(&conn).await
+}
+
+pub async fn schema_dump(config: &Config) -> anyhow::Result<()> {
+    print!("{}", snapshot_database(&config.database).await?.render());
+    Ok(())
+}
+
+/// Compares the `expected` schema with the `actual` one, printing the differences. Fails when they differ.
+pub fn report_diff(expected: &SchemaSnapshot, actual: &SchemaSnapshot) -> anyhow::Result<()> {
+    let diff = expected.diff(actual);
+    if diff.is_empty() {
+        println!("No schema drift");
+        return Ok(());
+    }
+
+    for line in &diff {
+        println!("{line}");
+    }
+    Err(anyhow!("schema drift: {} differences", diff.len()))
+}
+
+/// Compares two dumps, or with `scratch`, the schema the migrations produce in the scratch database with the dump, or
+/// with the database of the config when no dump is given.
+pub async fn schema_diff(
+    config_path: &str,
+    dump: Option<&Path>,
+    other_dump: Option<&Path>,
+    scratch: Option<&str>,
+) -> anyhow::Result<()> {
+    match (dump, other_dump, scratch) {
+        (Some(dump), Some(other_dump), None) => {
+            report_diff(&SchemaSnapshot::read(dump)?, &SchemaSnapshot::read(other_dump)?)
+        }
+        (Some(dump), None, Some(scratch)) => {
+            let config = Config::read(config_path)?;
+            report_diff(
+                &snapshot_scratch_database(&config.database, scratch).await?,
+                &SchemaSnapshot::read(dump)?,
+            )
+        }
+        (None, None, Some(scratch)) => {
+            let config = Config::read(config_path)?;
+            report_diff(
+                &snapshot_scratch_database(&config.database, scratch).await?,
+                &snapshot_database(&config.database).await?,
+            )
+        }
+        _ => Err(anyhow!(
+            "compare either two dumps, or a scratch database with a dump or the database"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(lines: &[&str]) -> SchemaSnapshot {
+        SchemaSnapshot {
+            lines: lines.iter().map(|line| normalize(line)).collect(),
+        }
+    }
+
+    #[test]
+    fn normalize_collapses_whitespace() {
+        assert_eq!(
+            normalize("function CREATE FUNCTION f()\n  RETURNS int\n\tLANGUAGE sql  "),
+            "function CREATE FUNCTION f() RETURNS int LANGUAGE sql"
+        );
+        assert_eq!(normalize("table public.stats"), "table public.stats");
+    }
+
+    #[test]
+    fn diff_lists_removed_then_added_lines() {
+        let expected = snapshot(&["table public.a", "table public.b", "column public.a 1 id integer"]);
+        let actual = snapshot(&["table public.a", "table public.c", "column public.a 1 id  integer"]);
+
+        assert_eq!(expected.diff(&actual), ["- table public.b", "+ table public.c"]);
+        assert!(expected.diff(&expected).is_empty());
+    }
+
+    #[test]
+    fn dumps_are_read_back_without_comments_and_blank_lines() {
+        let original = snapshot(&["table public.b", "table public.a", "view public.v as SELECT 1;"]);
+        let path = std::env::temp_dir().join(format!("shva-schema-dump-{}.txt", std::process::id()));
+        fs::write(&path, format!("{}\n-- edited by hand\n\n", original.render())).unwrap();
+
+        let read = SchemaSnapshot::read(&path);
+        fs::remove_file(&path).unwrap();
+
+        let read = read.unwrap();
+        assert!(original.diff(&read).is_empty());
+        assert_eq!(
+            read.render(),
+            "-- shva schema dump\ntable public.a\ntable public.b\nview public.v as SELECT 1;\n"
+        );
+    }
+}
@@ -25,7 +25,15 @@
 // IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
-use tokio::signal;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{signal, sync::watch};
 use tracing::info;
 
 pub async fn shutdown_signal() {
@@ -51,3 +59,32 @@ pub async fn shutdown_signal() {
 
     info!("Starting graceful shutdown: received {}", signal);
 }
+
+/// Waits for a shutdown signal in the background and broadcasts it, so that several servers can share it.
+///
+/// `draining` is set as soon as the signal is received, so that readiness starts failing, while the broadcast
+/// (i.e. servers stop accepting connections) is delayed by `pre_stop_delay` to give load balancers time to notice.
+pub fn shared_shutdown_signal(draining: Arc<AtomicBool>, pre_stop_delay: Duration) -> watch::Receiver<bool> {
+    let (sender, receiver) = watch::channel(false);
+
+    tokio::spawn(async move {
+        shutdown_signal().await;
+
+        draining.store(true, Ordering::Relaxed);
+        if !pre_stop_delay.is_zero() {
+            info!(
+                "Readiness is failing, waiting {:?} before refusing connections",
+                pre_stop_delay
+            );
+            tokio::time::sleep(pre_stop_delay).await;
+        }
+
+        let _ = sender.send(true);
+    });
+
+    receiver
+}
+
+pub async fn wait_for_shutdown(mut receiver: watch::Receiver<bool>) {
+    let _ = receiver.wait_for(|&shutdown| shutdown).await;
+}